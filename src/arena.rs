@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::Rng;
+
+use crate::connect_four::{
+    ConnectFourGame, ConnectFourModel, ConnectFourState, TranspositionTable, advance_root, search_connect_four, select_move,
+};
+use crate::search::SearchConfig;
+
+#[derive(Clone)]
+pub struct GatingConfig {
+    // Training steps between evaluations of the candidate against the best network.
+    pub interval: usize,
+    pub games: usize,
+    // Minimum score of the candidate, counting draws as half a win, for it to replace the best network.
+    pub threshold: f64,
+    pub search: SearchConfig,
+}
+
+impl Default for GatingConfig {
+    fn default() -> Self {
+        GatingConfig {
+            interval: 1_000,
+            games: 100,
+            threshold: 0.55,
+            search: SearchConfig {
+                simulations: 100,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct MatchResult {
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize,
+}
+
+impl MatchResult {
+    pub fn games(&self) -> usize {
+        return self.wins + self.draws + self.losses;
+    }
+
+    // Mean score per game, 1 for a win and 0.5 for a draw.
+    pub fn score(&self) -> f64 {
+        return (self.wins as f64 + 0.5 * self.draws as f64) / self.games() as f64;
+    }
+
+    // Normal approximation of the interval around `score` for the standard score `z`, 1.96 for 95%.
+    pub fn confidence_interval(&self, z: f64) -> (f64, f64) {
+        let score = self.score();
+        let games = self.games() as f64;
+
+        let variance =
+            (self.wins as f64 * (1f64 - score).powi(2) + self.draws as f64 * (0.5 - score).powi(2) + self.losses as f64 * score.powi(2))
+                / games;
+
+        let margin = z * (variance / games).sqrt();
+
+        return ((score - margin).max(0f64), (score + margin).min(1f64));
+    }
+}
+
+pub fn model_vs_model(
+    model_a: &ConnectFourModel,
+    model_b: &ConnectFourModel,
+    config: &SearchConfig,
+    display: bool,
+    random_start: bool,
+) -> i64 {
+    let mut game = ConnectFourGame::new();
+
+    if random_start {
+        let mut rng = rand::rng();
+
+        game.make_move(rng.random_range(0..7));
+        game.make_move(rng.random_range(0..7));
+    }
+
+    let mut state_a = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+    let mut state_b = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+    let mut table_a = TranspositionTable::new();
+    let mut table_b = TranspositionTable::new();
+
+    loop {
+        if display {
+            game.display();
+        }
+
+        let result = game.result();
+
+        if result != 0 || game.valid_moves().len() == 0 {
+            if display {
+                println!("Finished game with result {}", result);
+            }
+
+            return result;
+        }
+
+        let perspective = game.perspective;
+
+        let state = if perspective == 1 { state_a.clone() } else { state_b.clone() };
+        let table = if perspective == 1 { &mut table_a } else { &mut table_b };
+
+        let search_result = search_connect_four(
+            state.clone(),
+            &mut game,
+            if perspective == 1 { model_a } else { model_b },
+            config,
+            table,
+        );
+
+        if display {
+            let (_, score) = if perspective == 1 { model_a } else { model_b }.forward_batch(&[&game]);
+
+            println!("{}", search_result);
+            println!("Score {}", score.double_value(&[0]));
+            println!(
+                "Cache hit rate {}",
+                (if perspective == 1 { model_a } else { model_b }.cache().hit_rate() * 100f64).floor() / 100f64
+            );
+        }
+
+        let best_move = select_move(&state, config);
+
+        game.make_move(best_move);
+
+        state_a = advance_root(state_a, best_move, config, &mut table_a);
+        state_b = advance_root(state_b, best_move, config, &mut table_b);
+    }
+}
+
+// Plays `games` games from random openings, swapping colours every game, and returns the result for `candidate`.
+pub fn play_match(candidate: &ConnectFourModel, best: &ConnectFourModel, config: &SearchConfig, games: usize) -> MatchResult {
+    let mut result = MatchResult::default();
+
+    for i in 0..games {
+        let candidate_first = i % 2 == 0;
+
+        let outcome = if candidate_first {
+            model_vs_model(candidate, best, config, false, true)
+        } else {
+            -model_vs_model(best, candidate, config, false, true)
+        };
+
+        match outcome {
+            1 => result.wins += 1,
+            -1 => result.losses += 1,
+            _ => result.draws += 1,
+        }
+    }
+
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arena::MatchResult;
+
+    #[test]
+    fn test_match_result() {
+        let result = MatchResult {
+            wins: 60,
+            draws: 20,
+            losses: 20,
+        };
+
+        assert_eq!(result.score(), 0.7);
+
+        let (lower, upper) = result.confidence_interval(1.96);
+
+        assert!(lower < 0.7 && lower > 0.6);
+        assert!((upper - 0.7 - (0.7 - lower)).abs() < 1e-12);
+
+        let sweep = MatchResult {
+            wins: 10,
+            draws: 0,
+            losses: 0,
+        };
+
+        assert_eq!(sweep.confidence_interval(1.96), (1f64, 1f64));
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone)]
+pub struct Evaluation {
+    pub policy: Vec<f64>,
+    pub value: f64,
+}
+
+// Least recently used cache of network evaluations keyed by position hash. A capacity of 0 disables caching.
+pub struct EvaluationCache {
+    capacity: usize,
+    entries: HashMap<u64, (Evaluation, u64)>,
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl EvaluationCache {
+    pub fn new(capacity: usize) -> EvaluationCache {
+        return EvaluationCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        };
+    }
+
+    pub fn get(&mut self, hash: u64) -> Option<Evaluation> {
+        self.tick += 1;
+
+        match self.entries.get_mut(&hash) {
+            Some((evaluation, last_used)) => {
+                self.recency.remove(last_used);
+                self.recency.insert(self.tick, hash);
+
+                *last_used = self.tick;
+                self.hits += 1;
+
+                Some(evaluation.clone())
+            }
+            None => {
+                self.misses += 1;
+
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, hash: u64, evaluation: Evaluation) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+
+        if let Some((_, last_used)) = self.entries.insert(hash, (evaluation, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+
+        self.recency.insert(self.tick, hash);
+
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            return 0f64;
+        }
+
+        return self.hits as f64 / (self.hits + self.misses) as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Evaluation, EvaluationCache};
+
+    fn evaluation(value: f64) -> Evaluation {
+        Evaluation {
+            policy: vec![value],
+            value,
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = EvaluationCache::new(2);
+        cache.insert(1, evaluation(1f64));
+        cache.insert(2, evaluation(2f64));
+
+        assert_eq!(cache.get(1).unwrap().value, 1f64);
+
+        cache.insert(3, evaluation(3f64));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().value, 1f64);
+        assert_eq!(cache.get(3).unwrap().value, 3f64);
+
+        assert_eq!(cache.hits, 3);
+        assert_eq!(cache.misses, 1);
+        assert_eq!(cache.hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache = EvaluationCache::new(0);
+        cache.insert(1, evaluation(1f64));
+
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+}
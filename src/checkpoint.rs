@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tch::nn::VarStore;
+use tch::{Device, TchError, Tensor};
+
+use crate::connect_four::{Architecture, ConnectFourModel};
+use crate::optimizer::Optimizer;
+
+// Game every checkpoint header written by this crate names.
+const GAME: &str = "connect_four";
+
+// Metadata saved as the `header` entry of a checkpoint, one `key=value` per line so it stays readable with any tool
+// that can dump the tensor.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CheckpointHeader {
+    pub game: String,
+    pub architecture: Architecture,
+    // Whether the model carries the auxiliary moves left and opponent policy heads.
+    pub auxiliary: bool,
+    pub step: usize,
+    // File name of the checkpoint this one was trained from, None for the first of a run.
+    pub parent: Option<String>,
+    // Training hyperparameters by name, written as `training.<name>=<value>`.
+    pub hyperparameters: Vec<(String, String)>,
+}
+
+impl CheckpointHeader {
+    pub fn new(architecture: Architecture, step: usize, parent: Option<String>) -> CheckpointHeader {
+        return CheckpointHeader {
+            game: GAME.to_string(),
+            architecture,
+            auxiliary: false,
+            step,
+            parent,
+            hyperparameters: Vec::new(),
+        };
+    }
+
+    fn to_text(&self) -> String {
+        let mut lines = vec![format!("game={}", self.game)];
+
+        match self.architecture {
+            Architecture::Mlp => lines.push("architecture=mlp".to_string()),
+            Architecture::ResNet { blocks, channels } => {
+                lines.push("architecture=resnet".to_string());
+                lines.push(format!("blocks={}", blocks));
+                lines.push(format!("channels={}", channels));
+            }
+        }
+
+        lines.push(format!("auxiliary={}", self.auxiliary));
+        lines.push(format!("step={}", self.step));
+
+        if let Some(parent) = &self.parent {
+            lines.push(format!("parent={}", parent));
+        }
+
+        for (name, value) in &self.hyperparameters {
+            lines.push(format!("training.{}={}", name, value));
+        }
+
+        return lines.join("\n");
+    }
+
+    fn parse(text: &str) -> Result<CheckpointHeader, String> {
+        let fields: HashMap<&str, &str> = text.lines().filter_map(|line| line.split_once('=')).collect();
+
+        let field = |key: &str| fields.get(key).copied().ok_or_else(|| format!("missing {} in header", key));
+        let number = |key: &str| {
+            let value = field(key)?;
+
+            value.parse::<i64>().map_err(|_| format!("invalid {} {} in header", key, value))
+        };
+
+        let architecture = match field("architecture")? {
+            "mlp" => Architecture::Mlp,
+            "resnet" => Architecture::ResNet {
+                blocks: number("blocks")?,
+                channels: number("channels")?,
+            },
+            name => return Err(format!("unknown architecture {} in header", name)),
+        };
+
+        let hyperparameters = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("training.")?.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        return Ok(CheckpointHeader {
+            game: field("game")?.to_string(),
+            architecture,
+            auxiliary: fields.get("auxiliary") == Some(&"true"),
+            step: number("step")? as usize,
+            parent: fields.get("parent").map(|parent| parent.to_string()),
+            hyperparameters,
+        });
+    }
+}
+
+pub fn checkpoint_path(directory: &Path, step: usize) -> PathBuf {
+    return directory.join(format!("connect_four_{:05}.ckpt", step));
+}
+
+pub fn bundle_path(directory: &Path, step: usize) -> PathBuf {
+    return directory.join(format!("training_{:05}.bundle", step));
+}
+
+fn file_step(path: &Path, prefix: &str, suffix: &str) -> Option<usize> {
+    return path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(prefix))
+        .and_then(|name| name.strip_suffix(suffix))
+        .and_then(|step| step.parse::<usize>().ok());
+}
+
+// All `<prefix><step><suffix>` files in `directory`, sorted by step.
+fn list_steps(directory: &Path, prefix: &str, suffix: &str) -> Vec<(usize, PathBuf)> {
+    let mut checkpoints = Vec::new();
+
+    let Ok(entries) = fs::read_dir(directory) else {
+        return checkpoints;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if let Some(step) = file_step(&path, prefix, suffix) {
+            checkpoints.push((step, path));
+        }
+    }
+
+    checkpoints.sort();
+
+    return checkpoints;
+}
+
+// All `connect_four_<step>.ckpt` files in `directory`, sorted by step.
+pub fn list_checkpoints(directory: &Path) -> Vec<(usize, PathBuf)> {
+    return list_steps(directory, "connect_four_", ".ckpt");
+}
+
+pub fn latest_checkpoint(directory: &Path) -> Option<(usize, PathBuf)> {
+    return list_checkpoints(directory).pop();
+}
+
+// Writes the weights with `header` under a temporary name first, so readers never load a partially written checkpoint.
+pub fn save_checkpoint(path: &Path, var_store: &VarStore, header: &CheckpointHeader) -> Result<(), TchError> {
+    let mut tensors: Vec<(String, Tensor)> = var_store.variables().into_iter().collect();
+
+    tensors.push(("header".to_string(), Tensor::from_slice(header.to_text().as_bytes())));
+
+    let temporary = path.with_extension("ckpt.tmp");
+
+    Tensor::save_multi(&tensors, &temporary)?;
+    fs::rename(&temporary, path)?;
+
+    return Ok(());
+}
+
+// Header of a checkpoint. Checkpoints from before headers were written hold an MLP, their header is recovered from their
+// auxiliary head variables and their file name.
+pub fn read_header(path: &Path) -> Result<CheckpointHeader, TchError> {
+    let tensors = Tensor::load_multi(path)?;
+
+    if let Some((_, header)) = tensors.iter().find(|(name, _)| name == "header") {
+        let text = String::from_utf8(Vec::<u8>::try_from(header)?)
+            .map_err(|_| TchError::FileFormat(format!("header of {} is not UTF-8", path.display())))?;
+
+        return CheckpointHeader::parse(&text).map_err(|message| TchError::FileFormat(format!("{}: {}", path.display(), message)));
+    }
+
+    let step = file_step(path, "connect_four_", ".ckpt").unwrap_or(0);
+
+    return Ok(CheckpointHeader {
+        auxiliary: tensors.iter().any(|(name, _)| name.starts_with("moves_left_layer.")),
+        ..CheckpointHeader::new(Architecture::Mlp, step, None)
+    });
+}
+
+fn check_game(path: &Path, header: &CheckpointHeader) -> Result<(), TchError> {
+    if header.game != GAME {
+        return Err(TchError::FileFormat(format!(
+            "{} is a {} checkpoint, expected {}",
+            path.display(),
+            header.game,
+            GAME
+        )));
+    }
+
+    return Ok(());
+}
+
+// Builds the model a checkpoint was saved with, including its auxiliary heads, and loads its weights.
+pub fn load_model(path: &Path, device: Device) -> Result<(VarStore, ConnectFourModel), TchError> {
+    let header = read_header(path)?;
+
+    check_game(path, &header)?;
+
+    let mut var_store = VarStore::new(device);
+    let mut model = ConnectFourModel::with_architecture(&var_store.root(), header.architecture);
+
+    if header.auxiliary {
+        model = model.with_auxiliary_heads(&var_store.root());
+    }
+
+    var_store.load(path)?;
+
+    return Ok((var_store, model));
+}
+
+// Loads a checkpoint into the variables of an existing `model`, refusing checkpoints of another architecture or with
+// different auxiliary heads instead of failing on the first mismatched variable.
+pub fn load_weights(path: &Path, var_store: &mut VarStore, model: &ConnectFourModel) -> Result<CheckpointHeader, TchError> {
+    let header = read_header(path)?;
+
+    check_game(path, &header)?;
+
+    if header.architecture != model.architecture() {
+        return Err(TchError::FileFormat(format!(
+            "{} holds a {:?} model, expected {:?}",
+            path.display(),
+            header.architecture,
+            model.architecture()
+        )));
+    }
+
+    if header.auxiliary != model.has_auxiliary_heads() {
+        let heads = |auxiliary: bool| if auxiliary { "with" } else { "without" };
+
+        return Err(TchError::FileFormat(format!(
+            "{} holds a model {} auxiliary heads, expected one {} them",
+            path.display(),
+            heads(header.auxiliary),
+            heads(model.has_auxiliary_heads())
+        )));
+    }
+
+    var_store.load(path)?;
+
+    return Ok(header);
+}
+
+pub fn latest_bundle(directory: &Path) -> Option<(usize, PathBuf)> {
+    return list_steps(directory, "training_", ".bundle").pop();
+}
+
+// Run directories in `runs_directory`, named by the unix time the run started, newest first.
+pub fn list_runs(runs_directory: &Path) -> Vec<PathBuf> {
+    let mut runs: Vec<(u64, PathBuf)> = fs::read_dir(runs_directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .filter_map(|path| Some((path.file_name()?.to_str()?.parse::<u64>().ok()?, path)))
+        .collect();
+
+    runs.sort();
+
+    return runs.into_iter().rev().map(|(_, path)| path).collect();
+}
+
+// Everything needed to continue training where it stopped: weights, optimizer moments, the training step, the seed the
+// trainer's random number generator is derived from and the step of the checkpoint self-play follows. The step is also
+// the position in the learning rate schedule.
+pub fn save_bundle(
+    path: &Path,
+    var_store: &VarStore,
+    optimizer: &Optimizer,
+    step: usize,
+    seed: u64,
+    best_step: usize,
+) -> Result<(), TchError> {
+    let mut tensors = vec![(
+        "training".to_string(),
+        Tensor::from_slice(&[step as i64, seed as i64, best_step as i64]),
+    )];
+
+    for (name, variable) in var_store.variables() {
+        tensors.push((format!("weights.{}", name), variable));
+    }
+
+    for (name, tensor) in optimizer.state() {
+        tensors.push((format!("optimizer.{}", name), tensor));
+    }
+
+    // Written under a temporary name so a crash never leaves a partial bundle as the latest one.
+    let temporary = path.with_extension("bundle.tmp");
+
+    Tensor::save_multi(&tensors, &temporary)?;
+    fs::rename(&temporary, path)?;
+
+    return Ok(());
+}
+
+// Restores a bundle written by `save_bundle` into `var_store` and `optimizer`, returning the step, the seed and the step
+// of the best checkpoint.
+pub fn load_bundle(path: &Path, var_store: &VarStore, optimizer: &mut Optimizer) -> Result<(usize, u64, usize), TchError> {
+    let tensors: HashMap<String, Tensor> = Tensor::load_multi(path)?.into_iter().collect();
+
+    let mut weights = HashMap::new();
+    let mut optimizer_state = HashMap::new();
+
+    for (name, tensor) in &tensors {
+        if let Some(name) = name.strip_prefix("weights.") {
+            weights.insert(name.to_string(), tensor.shallow_clone());
+        } else if let Some(name) = name.strip_prefix("optimizer.") {
+            optimizer_state.insert(name.to_string(), tensor.shallow_clone());
+        }
+    }
+
+    tch::no_grad(|| {
+        for (name, mut variable) in var_store.variables() {
+            let weight = weights
+                .get(&name)
+                .ok_or_else(|| TchError::TensorNameNotFound(name.clone(), path.display().to_string()))?;
+
+            variable.f_copy_(weight)?;
+        }
+
+        return Ok::<(), TchError>(());
+    })?;
+
+    optimizer.load_state(&optimizer_state)?;
+
+    let training = tensors
+        .get("training")
+        .ok_or_else(|| TchError::TensorNameNotFound("training".to_string(), path.display().to_string()))?;
+
+    return Ok((
+        training.int64_value(&[0]) as usize,
+        training.int64_value(&[1]) as u64,
+        training.int64_value(&[2]) as usize,
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tch::nn::VarStore;
+    use tch::{Device, Kind};
+
+    use crate::checkpoint::{
+        CheckpointHeader, bundle_path, checkpoint_path, latest_bundle, list_runs, load_bundle, load_model, load_weights, read_header,
+        save_bundle, save_checkpoint,
+    };
+    use crate::connect_four::{Architecture, ConnectFourGame, ConnectFourModel};
+    use crate::optimizer::{Algorithm, Optimizer};
+    use crate::training::TrainingConfig;
+
+    fn optimizer(var_store: &VarStore) -> Optimizer {
+        return Optimizer::new(
+            var_store,
+            Algorithm::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+            },
+            1e-3,
+            0.01,
+        );
+    }
+
+    #[test]
+    fn test_bundle() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_bundle_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        fs::create_dir_all(&directory).unwrap();
+
+        let var_store = VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+        let mut trained = optimizer(&var_store);
+
+        let (policy, score) = model.forward(&ConnectFourGame::new());
+
+        trained.backward_step(&(policy.sum(Kind::Float) + score.sum(Kind::Float)));
+
+        let seed = u64::MAX - 1;
+
+        save_bundle(&bundle_path(&directory, 100), &var_store, &trained, 100, 1, 0).unwrap();
+        save_bundle(&bundle_path(&directory, 300), &var_store, &trained, 300, seed, 200).unwrap();
+
+        let (latest_step, path) = latest_bundle(&directory).unwrap();
+
+        assert_eq!(latest_step, 300);
+
+        let restored_var_store = VarStore::new(Device::Cpu);
+        let _restored_model = ConnectFourModel::new(&restored_var_store.root());
+        let mut restored = optimizer(&restored_var_store);
+
+        assert_eq!(load_bundle(&path, &restored_var_store, &mut restored).unwrap(), (300, seed, 200));
+        assert_eq!(restored.steps, 1);
+
+        let restored_variables = restored_var_store.variables();
+
+        for (name, variable) in var_store.variables() {
+            assert!(variable.equal(&restored_variables[&name]));
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_list_runs() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_runs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        for run in ["900", "1000", "notes"] {
+            fs::create_dir_all(directory.join(run)).unwrap();
+        }
+
+        fs::write(directory.join("2000"), b"").unwrap();
+
+        assert_eq!(list_runs(&directory), vec![directory.join("1000"), directory.join("900")]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_header() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_checkpoint_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        fs::create_dir_all(&directory).unwrap();
+
+        let architecture = Architecture::ResNet { blocks: 1, channels: 4 };
+
+        let var_store = VarStore::new(Device::Cpu);
+        let _model = ConnectFourModel::with_architecture(&var_store.root(), architecture).with_auxiliary_heads(&var_store.root());
+
+        let path = checkpoint_path(&directory, 200);
+        let header = CheckpointHeader {
+            auxiliary: true,
+            hyperparameters: TrainingConfig::default().hyperparameters(),
+            ..CheckpointHeader::new(architecture, 200, Some("connect_four_00100.ckpt".to_string()))
+        };
+
+        save_checkpoint(&path, &var_store, &header).unwrap();
+
+        let read = read_header(&path).unwrap();
+
+        assert_eq!(read, header);
+        assert!(read.hyperparameters.contains(&("batch_size".to_string(), "256".to_string())));
+
+        let (_, model) = load_model(&path, Device::Cpu).unwrap();
+
+        let game = ConnectFourGame::new();
+        let inputs = model.encode(&game).unsqueeze(0);
+        let legal = model.legal_mask(&game).unsqueeze(0);
+
+        assert_eq!(inputs.size(), vec![1, 3, 6, 7]);
+        assert!(model.forward_training(&inputs, &legal, false).2.is_some());
+
+        let mut mlp_var_store = VarStore::new(Device::Cpu);
+        let mlp = ConnectFourModel::new(&mlp_var_store.root());
+
+        assert!(load_weights(&path, &mut mlp_var_store, &mlp).is_err());
+
+        let mut plain_var_store = VarStore::new(Device::Cpu);
+        let plain = ConnectFourModel::with_architecture(&plain_var_store.root(), architecture);
+
+        let error = load_weights(&path, &mut plain_var_store, &plain).unwrap_err().to_string();
+
+        assert!(error.contains("with auxiliary heads, expected one without them"));
+
+        let mut auxiliary_var_store = VarStore::new(Device::Cpu);
+        let auxiliary = ConnectFourModel::with_architecture(&auxiliary_var_store.root(), architecture)
+            .with_auxiliary_heads(&auxiliary_var_store.root());
+
+        assert!(load_weights(&path, &mut auxiliary_var_store, &auxiliary).unwrap().auxiliary);
+
+        let legacy = checkpoint_path(&directory, 300);
+
+        mlp_var_store.save(&legacy).unwrap();
+
+        assert_eq!(read_header(&legacy).unwrap(), CheckpointHeader::new(Architecture::Mlp, 300, None));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
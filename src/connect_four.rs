@@ -1,13 +1,26 @@
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::time::Instant;
 
+use rand::distr::Distribution;
+use rand::distr::weighted::WeightedIndex;
+use rand_distr::{Gamma, Gumbel};
 use tch::nn::{Linear, Module, OptimizerConfig, Path};
 use tch::{Device, Kind, Tensor, nn};
 
+use crate::cache::{Evaluation, EvaluationCache};
+use crate::game::Game;
+use crate::search::{MoveStatistics, RootSelection, SearchConfig, SearchResult};
+use crate::zobrist::zobrist_keys;
+
+const ZOBRIST_KEYS: [u64; 2 * 6 * 7] = zobrist_keys(0xC0FFEE);
+
 pub struct ConnectFourGame {
     pub board_state: [i64; 6 * 7],
     pub perspective: i64,
     pub history: Vec<i64>,
+    pub hash: u64,
 }
 
 impl ConnectFourGame {
@@ -16,7 +29,22 @@ impl ConnectFourGame {
             board_state: [0; 6 * 7],
             perspective: 1,
             history: Vec::new(),
+            hash: 0,
+        }
+    }
+
+    pub fn from_moves(moves: &[i64]) -> Self {
+        let mut game = ConnectFourGame::new();
+
+        for &game_move in moves {
+            game.make_move(game_move);
         }
+
+        game
+    }
+
+    fn piece_key(&self, index: usize, piece: i64) -> u64 {
+        return ZOBRIST_KEYS[index * 2 + if piece == 1 { 0 } else { 1 }];
     }
 
     pub fn position_to_index(&self, x: i64, y: i64) -> usize {
@@ -31,6 +59,7 @@ impl ConnectFourGame {
         for row in 0..6 {
             if self.board_state[self.position_to_index(position, row)] == 0 {
                 self.board_state[self.position_to_index(position, row)] = self.perspective;
+                self.hash ^= self.piece_key(self.position_to_index(position, row), self.perspective);
 
                 break;
             }
@@ -46,6 +75,10 @@ impl ConnectFourGame {
 
         for i in 0..6 {
             if self.board_state[self.position_to_index(last_move, 5 - i)] != 0 {
+                self.hash ^= self.piece_key(
+                    self.position_to_index(last_move, 5 - i),
+                    self.board_state[self.position_to_index(last_move, 5 - i)],
+                );
                 self.board_state[self.position_to_index(last_move, 5 - i)] = 0;
 
                 break;
@@ -180,9 +213,42 @@ impl ConnectFourGame {
     }
 }
 
+impl Game for ConnectFourGame {
+    fn current_player(&self) -> usize {
+        return if self.perspective == 1 { 0 } else { 1 };
+    }
+
+    fn is_terminal(&self) -> bool {
+        return self.result() != 0 || self.valid_moves().is_empty();
+    }
+
+    fn returns(&self, player: usize) -> f64 {
+        return (self.result() * if player == 0 { 1 } else { -1 }) as f64;
+    }
+
+    fn symmetry_count() -> usize {
+        return 2;
+    }
+
+    fn transform_action(symmetry: usize, action: i64) -> i64 {
+        return if symmetry == 1 { 6 - action } else { action };
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::connect_four::ConnectFourGame;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use tch::{Device, Tensor, nn};
+
+    use crate::connect_four::{
+        Architecture, ConnectFourGame, ConnectFourModel, ConnectFourState, LossWeights, TranspositionTable, advance_root,
+        masked_log_softmax, search_connect_four, select_move,
+    };
+    use crate::game::Game;
+    use crate::search::{RootSelection, SearchConfig};
 
     #[test]
     fn test_results() {
@@ -291,97 +357,1009 @@ mod tests {
 
         assert_eq!(game.result(), -1);
     }
+
+    #[test]
+    fn test_hash() {
+        let mut game = ConnectFourGame::new();
+        game.make_move(0);
+        game.make_move(1);
+        game.make_move(2);
+
+        let mut transposed = ConnectFourGame::new();
+        transposed.make_move(2);
+        transposed.make_move(1);
+        transposed.make_move(0);
+
+        assert_eq!(game.hash, transposed.hash);
+
+        transposed.undo_move();
+        transposed.make_move(3);
+
+        assert_ne!(game.hash, transposed.hash);
+
+        for _ in 0..3 {
+            game.undo_move();
+        }
+
+        assert_eq!(game.hash, ConnectFourGame::new().hash);
+    }
+
+    #[test]
+    fn test_symmetry() {
+        let moves = [0, 1, 1, 2, 6];
+
+        let game = ConnectFourGame::from_moves(&moves);
+        let mirrored = ConnectFourGame::from_moves(&moves.map(|game_move| ConnectFourGame::transform_action(1, game_move)));
+
+        for y in 0..6 {
+            for x in 0..7 {
+                assert_eq!(
+                    game.board_state[game.position_to_index(x, y)],
+                    mirrored.board_state[mirrored.position_to_index(6 - x, y)]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_resnet() {
+        let architecture = Architecture::ResNet { blocks: 2, channels: 8 };
+
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::with_architecture(&var_store.root(), architecture);
+
+        let inputs = Tensor::stack(
+            &[
+                model.encode(&ConnectFourGame::new()),
+                model.encode(&ConnectFourGame::from_moves(&[3, 4])),
+            ],
+            0,
+        );
+
+        let legal = Tensor::ones([2, 7], (tch::Kind::Float, Device::Cpu));
+
+        let (policy, score) = model.forward_inputs(&inputs, &legal, true);
+
+        assert_eq!(policy.size(), vec![2, 7]);
+        assert_eq!(score.size(), vec![2]);
+
+        let planes = model.encode(&ConnectFourGame::from_moves(&[3]));
+
+        assert_eq!(planes.sum(tch::Kind::Float).double_value(&[]), 1f64);
+    }
+
+    #[test]
+    fn test_forward_batch() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let games = [
+            ConnectFourGame::new(),
+            ConnectFourGame::from_moves(&[3]),
+            ConnectFourGame::from_moves(&[3, 3]),
+        ];
+
+        let (policy, score) = model.forward_batch(&games.iter().collect::<Vec<_>>());
+
+        assert_eq!(policy.size(), vec![3, 7]);
+        assert_eq!(score.size(), vec![3]);
+        assert!(!policy.requires_grad());
+
+        let (single_policy, single_score) = model.forward(&games[1]);
+
+        assert!(policy.get(1).allclose(&single_policy, 1e-6, 1e-6, false));
+        assert!((score.double_value(&[1]) - single_score.double_value(&[0])).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_legal_mask() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let game = ConnectFourGame::from_moves(&[3, 3, 3, 3, 3, 3]);
+
+        let (policy, _) = model.forward_batch(&[&game]);
+
+        assert_eq!(policy.double_value(&[0, 3]), 0f64);
+        assert!((policy.sum(tch::Kind::Float).double_value(&[]) - 1f64).abs() < 1e-5);
+        assert_eq!(model.legal_mask(&game).sum(tch::Kind::Float).double_value(&[]), 6f64);
+    }
+
+    #[test]
+    fn test_auxiliary_heads() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root()).with_auxiliary_heads(&var_store.root());
+
+        let games = [ConnectFourGame::new(), ConnectFourGame::from_moves(&[3, 3, 3, 3, 3, 3])];
+
+        let inputs = Tensor::stack(&games.iter().map(|game| model.encode(game)).collect::<Vec<_>>(), 0);
+        let legal = Tensor::stack(&games.iter().map(|game| model.legal_mask(game)).collect::<Vec<_>>(), 0);
+
+        let (log_policy, _, auxiliary) = model.forward_training(&inputs, &legal, true);
+        let (moves_left, opponent_log_policy) = auxiliary.unwrap();
+
+        assert_eq!(moves_left.size(), vec![2]);
+        assert_eq!(opponent_log_policy.size(), vec![2, 7]);
+        assert_eq!(opponent_log_policy.exp().double_value(&[1, 3]), 0f64);
+        assert!(log_policy.allclose(&model.forward_log_policy(&inputs, &legal, true).0, 1e-6, 1e-6, false));
+
+        let mut target_opponent_policy = [0f32; 14];
+        target_opponent_policy[0] = 1f32;
+        target_opponent_policy[7 + 4] = 1f32;
+
+        let loss = model.auxiliary_loss(
+            &moves_left,
+            &opponent_log_policy,
+            &Tensor::from_slice(&[42f32, 36f32]),
+            &Tensor::from_slice(&target_opponent_policy).view([2, 7]),
+        );
+
+        assert_eq!(loss.size(), vec![2]);
+        assert!(bool::try_from(loss.isfinite().all()).unwrap());
+        assert!(loss.double_value(&[0]) > 0f64);
+    }
+
+    #[test]
+    fn test_loss_underflow() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        // Column 1 has a probability far below what a float can hold and column 6 is masked out.
+        let logits = Tensor::from_slice(&[100f32, -100f32, 0f32, 0f32, 0f32, 0f32, 0f32]).view([1, 7]);
+        let legal = Tensor::from_slice(&[1f32, 1f32, 1f32, 1f32, 1f32, 1f32, 0f32]).view([1, 7]);
+
+        let log_policy = masked_log_softmax(&logits, &legal);
+
+        let target_policy = Tensor::from_slice(&[0f32, 1f32, 0f32, 0f32, 0f32, 0f32, 0f32]).view([1, 7]);
+        let target_score = Tensor::from_slice(&[0f32]);
+
+        let loss = model.loss(&log_policy, &target_score, &target_policy, &target_score, &LossWeights::default());
+
+        assert!((loss.double_value(&[]) - 200f64).abs() < 1e-3);
+
+        let weights = LossWeights {
+            policy: 0.5,
+            value: 2f64,
+            l2: 0f64,
+        };
+
+        let score = Tensor::from_slice(&[1f32]);
+
+        let loss = model.loss(&log_policy, &score, &target_policy, &target_score, &weights);
+
+        assert!((loss.double_value(&[]) - 102f64).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_solver() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let mut game = ConnectFourGame::new();
+
+        for _ in 0..3 {
+            game.make_move(0);
+            game.make_move(1);
+        }
+
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+        let config = SearchConfig::default();
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut TranspositionTable::new());
+
+        assert_eq!(state.borrow().child(0).unwrap().borrow().proven, Some(1));
+        assert_eq!(state.borrow().proven, Some(-1));
+        assert_eq!(select_move(&state, &config), 0);
+    }
+
+    #[test]
+    fn test_sequential_halving() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = SearchConfig {
+            simulations: 8,
+            root_selection: RootSelection::SequentialHalving {
+                considered_moves: 4,
+                c_visit: 50f64,
+                c_scale: 0.1,
+            },
+            ..Default::default()
+        };
+
+        let mut game = ConnectFourGame::new();
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut TranspositionTable::new());
+
+        let state = state.borrow();
+
+        let total: f64 = state.policy_target.as_ref().unwrap().iter().sum();
+
+        assert!((total - 1f64).abs() < 1e-9);
+
+        let considered: Vec<i64> = state
+            .moves
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|child| child.borrow().visits > 0)
+            .map(|child| child.borrow().game_move.unwrap())
+            .collect();
+
+        assert!(considered.len() <= 4);
+        assert!(considered.contains(&state.selected_move.unwrap()));
+        assert!(state.visits as usize <= config.simulations);
+    }
+
+    #[test]
+    fn test_sequential_halving_time_budget() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        // Two phases of 8 visits for each of the 4 considered moves, the budget runs out during the first.
+        let config = SearchConfig {
+            simulations: 64,
+            time_budget: Some(Duration::ZERO),
+            root_selection: RootSelection::SequentialHalving {
+                considered_moves: 4,
+                c_visit: 50f64,
+                c_scale: 0.1,
+            },
+            ..Default::default()
+        };
+
+        let mut game = ConnectFourGame::new();
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut TranspositionTable::new());
+
+        let state = state.borrow();
+
+        assert_eq!(state.visits, 1 + 4 * 8);
+        assert!(state.selected_move.is_some());
+        assert!(state.policy_target.is_some());
+    }
+
+    #[test]
+    fn test_transpositions() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = SearchConfig {
+            simulations: 50,
+            transpositions: true,
+            ..Default::default()
+        };
+
+        let mut table = TranspositionTable::new();
+
+        let mut game = ConnectFourGame::from_moves(&[0, 1, 2]);
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut table);
+
+        assert!(table.len() > 0);
+
+        // Every reply is lost for its mover, the same position reached through another move order must be solved.
+        for child in state.borrow().moves.as_ref().unwrap() {
+            child.borrow_mut().proven = Some(-1);
+        }
+
+        let mut transposed = ConnectFourGame::from_moves(&[2, 1, 0]);
+        let transposed_state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(transposed_state.clone(), &mut transposed, &model, &config, &mut table);
+
+        assert!(Rc::ptr_eq(
+            &state.borrow().moves.as_ref().unwrap()[0],
+            &transposed_state.borrow().moves.as_ref().unwrap()[0]
+        ));
+        assert_eq!(transposed_state.borrow().proven, Some(1));
+        assert!(select_move(&transposed_state, &config) >= 0);
+    }
+
+    #[test]
+    fn test_advance_root() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = SearchConfig {
+            simulations: 100,
+            ..Default::default()
+        };
+
+        let mut table = TranspositionTable::new();
+
+        let mut game = ConnectFourGame::new();
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut table);
+
+        let best_move = select_move(&state, &config);
+        let child = state.borrow().child(best_move).unwrap();
+        let visits = child.borrow().visits;
+
+        assert!(visits > 0);
+
+        game.make_move(best_move);
+
+        let root = advance_root(state, best_move, &config, &mut table);
+
+        assert!(Rc::ptr_eq(&root, &child));
+        assert_eq!(root.borrow().visits, visits);
+
+        search_connect_four(root.clone(), &mut game, &model, &config, &mut table);
+
+        assert!(root.borrow().visits > visits);
+
+        let fresh_config = SearchConfig {
+            reuse_tree: false,
+            ..config
+        };
+
+        let reply = select_move(&root, &fresh_config);
+        let fresh = advance_root(root, reply, &fresh_config, &mut table);
+
+        assert_eq!(fresh.borrow().visits, 0);
+        assert!(fresh.borrow().moves.is_none());
+        assert_eq!(fresh.borrow().game_move, Some(reply));
+    }
+
+    #[test]
+    fn test_select_move() {
+        let mut state = ConnectFourState::new(0f64);
+
+        state.moves = Some(
+            [(2, 3), (3, 10), (4, 5)]
+                .into_iter()
+                .map(|(game_move, visits)| {
+                    let mut child = ConnectFourState::new(1f64 / 3f64);
+
+                    child.game_move = Some(game_move);
+                    child.visits = visits;
+
+                    Rc::new(RefCell::new(child))
+                })
+                .collect(),
+        );
+
+        let state = Rc::new(RefCell::new(state));
+
+        let config = SearchConfig {
+            temperature: 0f64,
+            ..Default::default()
+        };
+
+        for _ in 0..10 {
+            assert_eq!(select_move(&state, &config), 3);
+        }
+
+        let sampled = SearchConfig {
+            temperature: 1f64,
+            ..Default::default()
+        };
+
+        assert!([2, 3, 4].contains(&select_move(&state, &sampled)));
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let budget = Duration::from_millis(50);
+        let config = SearchConfig {
+            simulations: 1,
+            time_budget: Some(budget),
+            ..Default::default()
+        };
+
+        let mut game = ConnectFourGame::new();
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        let start = Instant::now();
+        let result = search_connect_four(state, &mut game, &model, &config, &mut TranspositionTable::new());
+
+        assert!(start.elapsed() >= budget);
+        assert!(result.visits > 1);
+    }
+
+    #[test]
+    fn test_root_noise() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = SearchConfig {
+            simulations: 50,
+            dirichlet_fraction: 0.25,
+            transpositions: true,
+            ..Default::default()
+        };
+
+        let mut game = ConnectFourGame::new();
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        search_connect_four(state.clone(), &mut game, &model, &config, &mut TranspositionTable::new());
+
+        let evaluation = model.evaluate(&game);
+        let state = state.borrow();
+
+        // The noise stays on the root, the shared children keep the network priors.
+        for child in state.moves.as_ref().unwrap() {
+            let child = child.borrow();
+
+            assert_eq!(child.policy, evaluation.policy[child.game_move.unwrap() as usize]);
+        }
+
+        let noisy_policy = state.noisy_policy.as_ref().unwrap();
+
+        assert_eq!(noisy_policy.len(), 7);
+        assert!((noisy_policy.iter().sum::<f64>() - 1f64).abs() < 1e-6);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Architecture {
+    // Two hidden layers of 100 units over the board from the perspective of the side to move.
+    Mlp,
+    // Planes for own stones, opponent stones and the side to move, a 3x3 convolution and `blocks` residual blocks of
+    // `channels` channels, followed by separate policy and value heads.
+    ResNet { blocks: i64, channels: i64 },
+}
+
+struct ResidualBlock {
+    first_conv: nn::Conv2D,
+    first_norm: nn::BatchNorm,
+    second_conv: nn::Conv2D,
+    second_norm: nn::BatchNorm,
+}
+
+impl ResidualBlock {
+    fn new(vs: &Path, channels: i64) -> ResidualBlock {
+        let config = nn::ConvConfig {
+            padding: 1,
+            bias: false,
+            ..Default::default()
+        };
+
+        return ResidualBlock {
+            first_conv: nn::conv2d(vs / "first_conv", channels, channels, 3, config),
+            first_norm: nn::batch_norm2d(vs / "first_norm", channels, Default::default()),
+            second_conv: nn::conv2d(vs / "second_conv", channels, channels, 3, config),
+            second_norm: nn::batch_norm2d(vs / "second_norm", channels, Default::default()),
+        };
+    }
+
+    fn forward_t(&self, inputs: &Tensor, train: bool) -> Tensor {
+        let value = inputs.apply(&self.first_conv).apply_t(&self.first_norm, train).relu();
+        let value = value.apply(&self.second_conv).apply_t(&self.second_norm, train);
+
+        return (value + inputs).relu();
+    }
+}
+
+enum Network {
+    Mlp {
+        input_layer: Linear,
+        hidden_layer: Linear,
+        output_layer: Linear,
+    },
+    ResNet {
+        input_conv: nn::Conv2D,
+        input_norm: nn::BatchNorm,
+        blocks: Vec<ResidualBlock>,
+        policy_conv: nn::Conv2D,
+        policy_norm: nn::BatchNorm,
+        policy_layer: Linear,
+        value_conv: nn::Conv2D,
+        value_norm: nn::BatchNorm,
+        value_hidden_layer: Linear,
+        value_layer: Linear,
+    },
+}
+
+// Optional heads predicting the moves left in the game and the opponent's reply, trained only as extra signal.
+struct AuxiliaryHeads {
+    moves_left: Linear,
+    opponent_policy: Linear,
+}
+
+// Weights of the terms of the training loss, `l2` scales the sum of squared parameters on top of any optimizer weight
+// decay.
+#[derive(Clone, Copy)]
+pub struct LossWeights {
+    pub policy: f64,
+    pub value: f64,
+    pub l2: f64,
+}
+
+impl Default for LossWeights {
+    fn default() -> Self {
+        LossWeights {
+            policy: 1f64,
+            value: 1f64,
+            l2: 0f64,
+        }
+    }
 }
 
 pub struct ConnectFourModel {
-    input_layer: Linear,
-    hidden_layer: Linear,
-    output_layer: Linear,
+    architecture: Architecture,
+    // Device of the variable store the model was built on, inputs are moved there.
+    device: Device,
+    network: Network,
+    auxiliary: Option<AuxiliaryHeads>,
+
+    cache: RefCell<EvaluationCache>,
 }
 
 impl ConnectFourModel {
     pub fn new(vs: &Path) -> Self {
-        let input_layer = nn::linear(vs / "input_layer", 6 * 7, 100, Default::default());
-        let hidden_layer = nn::linear(vs / "hidden_layer", 100, 100, Default::default());
-        let output_layer = nn::linear(vs / "output_layer", 100, 7 + 1, Default::default());
+        return ConnectFourModel::with_architecture(vs, Architecture::Mlp);
+    }
+
+    pub fn with_architecture(vs: &Path, architecture: Architecture) -> Self {
+        let network = match architecture {
+            Architecture::Mlp => Network::Mlp {
+                input_layer: nn::linear(vs / "input_layer", 6 * 7, 100, Default::default()),
+                hidden_layer: nn::linear(vs / "hidden_layer", 100, 100, Default::default()),
+                output_layer: nn::linear(vs / "output_layer", 100, 7 + 1, Default::default()),
+            },
+            Architecture::ResNet { blocks, channels } => {
+                let config = nn::ConvConfig {
+                    padding: 1,
+                    bias: false,
+                    ..Default::default()
+                };
+
+                Network::ResNet {
+                    input_conv: nn::conv2d(vs / "input_conv", 3, channels, 3, config),
+                    input_norm: nn::batch_norm2d(vs / "input_norm", channels, Default::default()),
+                    blocks: (0..blocks)
+                        .map(|i| ResidualBlock::new(&(vs / format!("block_{}", i)), channels))
+                        .collect(),
+                    policy_conv: nn::conv2d(vs / "policy_conv", channels, 2, 1, Default::default()),
+                    policy_norm: nn::batch_norm2d(vs / "policy_norm", 2, Default::default()),
+                    policy_layer: nn::linear(vs / "policy_layer", 2 * 6 * 7, 7, Default::default()),
+                    value_conv: nn::conv2d(vs / "value_conv", channels, 1, 1, Default::default()),
+                    value_norm: nn::batch_norm2d(vs / "value_norm", 1, Default::default()),
+                    value_hidden_layer: nn::linear(vs / "value_hidden_layer", 6 * 7, 64, Default::default()),
+                    value_layer: nn::linear(vs / "value_layer", 64, 1, Default::default()),
+                }
+            }
+        };
 
         ConnectFourModel {
-            input_layer,
-            hidden_layer,
-            output_layer,
+            architecture,
+            device: vs.device(),
+            network,
+            auxiliary: None,
+            cache: RefCell::new(EvaluationCache::new(0)),
         }
     }
 
-    pub fn forward(&self, game: &ConnectFourGame) -> (Tensor, Tensor) {
-        let mut value = (Tensor::from_slice(&game.board_state) * game.perspective)
-            .to_kind(Kind::Float)
-            .set_requires_grad(true)
-            .to_device(Device::cuda_if_available());
+    // Size of the shared features the auxiliary heads read, the last hidden layer of the MLP or the pooled trunk.
+    fn feature_size(&self) -> i64 {
+        match self.architecture {
+            Architecture::Mlp => 100,
+            Architecture::ResNet { channels, .. } => channels,
+        }
+    }
+
+    pub fn with_auxiliary_heads(self, vs: &Path) -> Self {
+        let feature_size = self.feature_size();
+
+        ConnectFourModel {
+            auxiliary: Some(AuxiliaryHeads {
+                moves_left: nn::linear(vs / "moves_left_layer", feature_size, 1, Default::default()),
+                opponent_policy: nn::linear(vs / "opponent_policy_layer", feature_size, 7, Default::default()),
+            }),
+            ..self
+        }
+    }
+
+    pub fn device(&self) -> Device {
+        return self.device;
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        return self.architecture;
+    }
+
+    pub fn has_auxiliary_heads(&self) -> bool {
+        return self.auxiliary.is_some();
+    }
+
+    pub fn with_cache(self, capacity: usize) -> Self {
+        ConnectFourModel {
+            cache: RefCell::new(EvaluationCache::new(capacity)),
+            ..self
+        }
+    }
+
+    pub fn cache(&self) -> Ref<'_, EvaluationCache> {
+        return self.cache.borrow();
+    }
+
+    // Must be called whenever the weights change, cached evaluations are only valid for the weights that produced them.
+    pub fn clear_cache(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    pub fn evaluate(&self, game: &ConnectFourGame) -> Evaluation {
+        if let Some(evaluation) = self.cache.borrow_mut().get(game.hash) {
+            return evaluation;
+        }
+
+        let (policy, score) = self.forward_batch(&[game]);
+
+        let evaluation = Evaluation {
+            policy: Vec::<f64>::try_from(policy.squeeze_dim(0).to_kind(Kind::Double).to_device(Device::Cpu)).unwrap(),
+            value: score.double_value(&[0]),
+        };
+
+        self.cache.borrow_mut().insert(game.hash, evaluation.clone());
+
+        return evaluation;
+    }
+
+    // Network input for `game`, the board times the side to move [42] for the MLP and planes [3, 6, 7] for the ResNet.
+    pub fn encode(&self, game: &ConnectFourGame) -> Tensor {
+        let board = (Tensor::from_slice(&game.board_state) * game.perspective).to_kind(Kind::Float);
+
+        match self.architecture {
+            Architecture::Mlp => board,
+            Architecture::ResNet { .. } => {
+                let own = board.eq(1f64).to_kind(Kind::Float);
+                let opponent = board.eq(-1f64).to_kind(Kind::Float);
+                let side = board.full_like(if game.perspective == 1 { 1f64 } else { 0f64 });
+
+                Tensor::stack(&[own, opponent, side], 0).view([3, 6, 7])
+            }
+        }
+    }
+
+    // 1 for the legal columns of `game` and 0 for full ones [7], masks the policy head in inference and training.
+    pub fn legal_mask(&self, game: &ConnectFourGame) -> Tensor {
+        let mask: Vec<f32> = (0..7).map(|x| if game.move_valid(x) { 1f32 } else { 0f32 }).collect();
+
+        return Tensor::from_slice(&mask);
+    }
+
+    // Policy logits [batch, 7], raw values [batch] and the features [batch, feature_size] of encoded positions.
+    fn forward_t(&self, inputs: &Tensor, train: bool) -> (Tensor, Tensor, Tensor) {
+        match &self.network {
+            Network::Mlp {
+                input_layer,
+                hidden_layer,
+                output_layer,
+            } => {
+                let features = inputs.apply(input_layer).relu().apply(hidden_layer).relu();
+                let value = features.apply(output_layer);
+
+                (value.narrow(1, 0, 7), value.narrow(1, 7, 1).squeeze_dim(1), features)
+            }
+            Network::ResNet {
+                input_conv,
+                input_norm,
+                blocks,
+                policy_conv,
+                policy_norm,
+                policy_layer,
+                value_conv,
+                value_norm,
+                value_hidden_layer,
+                value_layer,
+            } => {
+                let mut trunk = inputs.apply(input_conv).apply_t(input_norm, train).relu();
+
+                for block in blocks {
+                    trunk = block.forward_t(&trunk, train);
+                }
+
+                let policy = trunk
+                    .apply(policy_conv)
+                    .apply_t(policy_norm, train)
+                    .relu()
+                    .flatten(1, -1)
+                    .apply(policy_layer);
+
+                let value = trunk
+                    .apply(value_conv)
+                    .apply_t(value_norm, train)
+                    .relu()
+                    .flatten(1, -1)
+                    .apply(value_hidden_layer)
+                    .relu()
+                    .apply(value_layer)
+                    .squeeze_dim(1);
+
+                let features = trunk.mean_dim(&[2i64, 3][..], false, Kind::Float);
+
+                (policy, value, features)
+            }
+        }
+    }
+
+    // Batched forward over encoded positions, returns policies [batch, 7] and values [batch]. `train` updates the batch
+    // normalisation statistics of the ResNet.
+    pub fn forward_inputs(&self, inputs: &Tensor, legal: &Tensor, train: bool) -> (Tensor, Tensor) {
+        let (log_policy, value) = self.forward_log_policy(inputs, legal, train);
+
+        (log_policy.exp(), value)
+    }
+
+    // Log-softmax over the legal moves given by `legal` [batch, 7], illegal moves get a large finite negative logit
+    // rather than -inf so a zero target times their log probability stays zero.
+    pub fn forward_log_policy(&self, inputs: &Tensor, legal: &Tensor, train: bool) -> (Tensor, Tensor) {
+        let (policy, value, _) = self.forward_t(inputs, train);
+
+        (masked_log_softmax(&policy, legal), value.tanh())
+    }
+
+    // `forward_log_policy` together with the auxiliary heads, moves left [batch] and opponent log policies [batch, 7],
+    // from a single pass through the trunk. The auxiliary outputs are None when the model was built without them. Columns
+    // full in the position are also masked for the opponent, the column the reply fills up is left to the target.
+    pub fn forward_training(&self, inputs: &Tensor, legal: &Tensor, train: bool) -> (Tensor, Tensor, Option<(Tensor, Tensor)>) {
+        let (policy, value, features) = self.forward_t(inputs, train);
 
-        value = value.apply(&self.input_layer).relu();
-        value = value.apply(&self.hidden_layer).relu();
-        value = value.apply(&self.output_layer);
+        let auxiliary = self.auxiliary.as_ref().map(|auxiliary| {
+            let moves_left = features.apply(&auxiliary.moves_left).squeeze_dim(1).sigmoid() * 42;
+            let opponent_log_policy = masked_log_softmax(&features.apply(&auxiliary.opponent_policy), legal);
 
-        let policy = value.narrow(0, 0, 7);
-        let score = value.narrow(0, 7, 1);
+            (moves_left, opponent_log_policy)
+        });
 
-        let policy = policy.softmax(0, Kind::Float);
-        let score = score.tanh();
+        (masked_log_softmax(&policy, legal), value.tanh(), auxiliary)
+    }
+
+    // Inference over a batch of positions without tracking gradients, returns policies [batch, 7] and values [batch].
+    pub fn forward_batch(&self, games: &[&ConnectFourGame]) -> (Tensor, Tensor) {
+        let inputs: Vec<Tensor> = games.iter().map(|game| self.encode(game)).collect();
+        let inputs = Tensor::stack(&inputs, 0).to_device(self.device);
+
+        let legal: Vec<Tensor> = games.iter().map(|game| self.legal_mask(game)).collect();
+        let legal = Tensor::stack(&legal, 0).to_device(self.device);
+
+        return tch::no_grad(|| self.forward_inputs(&inputs, &legal, false));
+    }
+
+    // Single position forward that keeps the graph for training, returns the policy [7] and value [1].
+    pub fn forward(&self, game: &ConnectFourGame) -> (Tensor, Tensor) {
+        let inputs = self.encode(game).unsqueeze(0).to_device(self.device);
+        let legal = self.legal_mask(game).unsqueeze(0).to_device(self.device);
 
-        (policy, score)
+        let (policy, score) = self.forward_inputs(&inputs, &legal, false);
+
+        (policy.squeeze_dim(0), score)
     }
 
-    pub fn loss(&self, policy: &Tensor, score: &Tensor, target_policy: &Tensor, target_score: &Tensor) -> Tensor {
-        println!("Log > {}", policy.log());
-        println!("Difference > {}", target_policy * policy.log());
-        println!("Neg Sum > {}", -(target_policy * policy.log()).sum(Kind::Float));
+    // Mean loss over a batch of outputs of `forward_log_policy`, the L2 term is left to the caller since the model does not
+    // own its variables.
+    pub fn loss(
+        &self,
+        log_policy: &Tensor,
+        score: &Tensor,
+        target_policy: &Tensor,
+        target_score: &Tensor,
+        weights: &LossWeights,
+    ) -> Tensor {
+        return self
+            .batch_loss(log_policy, score, target_policy, target_score, weights)
+            .mean(Kind::Float);
+    }
 
-        let policy_loss = -(target_policy * policy.log()).sum(Kind::Float);
+    // Per sample losses of shape [batch] for the outputs of `forward_log_policy`.
+    pub fn batch_loss(
+        &self,
+        log_policy: &Tensor,
+        score: &Tensor,
+        target_policy: &Tensor,
+        target_score: &Tensor,
+        weights: &LossWeights,
+    ) -> Tensor {
+        let policy_loss = -(target_policy * log_policy).sum_dim_intlist(1, false, Kind::Float);
 
         let value_loss = (score - target_score).pow_tensor_scalar(2);
 
-        policy_loss + value_loss
+        policy_loss * weights.policy + value_loss * weights.value
     }
+
+    // Per sample losses of shape [batch] for the auxiliary outputs of `forward_training`, moves left are compared as a
+    // fraction of the longest possible game.
+    pub fn auxiliary_loss(
+        &self,
+        moves_left: &Tensor,
+        opponent_log_policy: &Tensor,
+        target_moves_left: &Tensor,
+        target_opponent_policy: &Tensor,
+    ) -> Tensor {
+        let moves_left_loss = ((moves_left - target_moves_left) / 42).pow_tensor_scalar(2);
+
+        let opponent_policy_loss = -(target_opponent_policy * opponent_log_policy).sum_dim_intlist(1, false, Kind::Float);
+
+        moves_left_loss + opponent_policy_loss
+    }
+}
+
+// Log-softmax of `logits` [batch, 7] over the moves where `legal` is 1.
+fn masked_log_softmax(logits: &Tensor, legal: &Tensor) -> Tensor {
+    return logits.masked_fill(&legal.eq(0f64), -1e9).log_softmax(1, Kind::Float);
 }
 
 pub struct ConnectFourState {
     pub game_move: Option<i64>,
-    pub parent: Option<Rc<RefCell<ConnectFourState>>>,
 
     pub visits: i64,
-    pub score_total: i64,
+    pub score_total: f64,
     pub policy: f64,
 
+    // Exact result once the position is solved, from the perspective of the player who made `game_move`.
+    pub proven: Option<i64>,
+
     pub moves: Option<Vec<Rc<RefCell<ConnectFourState>>>>,
+
+    // Set on roots searched with sequential halving, the move it chose and the completed Q policy over `moves`.
+    pub selected_move: Option<i64>,
+    pub policy_target: Option<Vec<f64>>,
+
+    // Priors of `moves` with Dirichlet noise, set on roots searched with noise. Kept on the root rather than written into
+    // the children, which transpositions may share with other positions.
+    pub noisy_policy: Option<Vec<f64>>,
 }
 
 impl ConnectFourState {
-    pub fn new(parent: Option<Rc<RefCell<ConnectFourState>>>, policy: f64) -> ConnectFourState {
+    pub fn new(policy: f64) -> ConnectFourState {
         return ConnectFourState {
             game_move: None,
-            parent: parent,
             visits: 0,
-            score_total: 0,
+            score_total: 0f64,
             policy: policy,
+            proven: None,
             moves: None,
+            selected_move: None,
+            policy_target: None,
+            noisy_policy: None,
         };
     }
 
-    pub fn get_score(&self) -> f64 {
-        let exploration = 0.5f64;
+    pub fn child(&self, game_move: i64) -> Option<Rc<RefCell<ConnectFourState>>> {
+        return self
+            .moves
+            .as_ref()?
+            .iter()
+            .find(|child| child.borrow().game_move == Some(game_move))
+            .cloned();
+    }
+
+    // Priors the search selects `moves` by, the noisy ones on a root searched with noise.
+    pub fn priors(&self) -> Vec<f64> {
+        if let Some(noisy_policy) = &self.noisy_policy {
+            return noisy_policy.clone();
+        }
+
+        return self.moves.iter().flatten().map(|game_move| game_move.borrow().policy).collect();
+    }
 
-        let parent = self.parent.as_ref().unwrap().borrow();
+    pub fn get_score(&self, prior: f64, parent_visits: i64, config: &SearchConfig) -> f64 {
+        let exploration = config.c_puct * prior * (parent_visits as f64).sqrt() / (1f64 + self.visits as f64);
 
         if self.visits == 0 {
-            return exploration * self.policy * (parent.visits as f64).sqrt() / (1f64 + self.visits as f64);
+            return config.fpu + exploration;
         }
 
-        return self.score_total as f64 / self.visits as f64
-            + exploration * self.policy * (parent.visits as f64).sqrt() / (1f64 + self.visits as f64);
+        return self.score_total / self.visits as f64 + exploration;
+    }
+
+    pub fn search_result(&self) -> SearchResult {
+        let total_visits: i64 = self.moves.iter().flatten().map(|game_move| game_move.borrow().visits).sum();
+
+        let moves = self
+            .moves
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, game_move)| {
+                let game_move = game_move.borrow();
+
+                let target = match &self.policy_target {
+                    Some(policy_target) => policy_target[index],
+                    None if total_visits > 0 => game_move.visits as f64 / total_visits as f64,
+                    None => 0f64,
+                };
+
+                MoveStatistics {
+                    game_move: game_move.game_move.unwrap(),
+                    visits: game_move.visits,
+                    value: if game_move.visits > 0 {
+                        Some(game_move.score_total / game_move.visits as f64)
+                    } else {
+                        None
+                    },
+                    prior: game_move.policy,
+                    target,
+                    proven: game_move.proven,
+                }
+            })
+            .collect();
+
+        let mut principal_variation = Vec::new();
+        let mut best_move = self.best_child();
+
+        while let Some(game_move) = best_move {
+            principal_variation.push(game_move.borrow().game_move.unwrap());
+
+            best_move = game_move.borrow().best_child();
+        }
+
+        return SearchResult {
+            visits: self.visits,
+            value: if self.visits > 0 {
+                -self.score_total / self.visits as f64
+            } else {
+                0f64
+            },
+            moves,
+            principal_variation,
+        };
+    }
+
+    fn best_child(&self) -> Option<Rc<RefCell<ConnectFourState>>> {
+        return self
+            .moves
+            .iter()
+            .flatten()
+            .filter(|game_move| game_move.borrow().visits > 0)
+            .max_by_key(|game_move| {
+                let game_move = game_move.borrow();
+
+                (game_move.proven == Some(1), game_move.visits)
+            })
+            .cloned();
+    }
+
+    // A node is lost for its mover as soon as one reply wins, and solved to the best reply once every reply is solved.
+    fn update_proven(&mut self) {
+        let moves = self.moves.as_ref().unwrap();
+
+        if moves.iter().any(|game_move| game_move.borrow().proven == Some(1)) {
+            self.proven = Some(-1);
+        } else if moves.iter().all(|game_move| game_move.borrow().proven.is_some()) {
+            self.proven = moves.iter().map(|game_move| -game_move.borrow().proven.unwrap()).min();
+        }
     }
 }
 
-pub fn mcts_connect_four(node: Rc<RefCell<ConnectFourState>>, game: &mut ConnectFourGame, model: &ConnectFourModel, display: bool) -> i64 {
+// Positions reached through different move orders share the children of the first node expanded for them,
+// so search done below a transposition benefits every path into it.
+pub struct TranspositionTable {
+    nodes: HashMap<u64, Rc<RefCell<ConnectFourState>>>,
+}
+
+impl TranspositionTable {
+    pub fn new() -> TranspositionTable {
+        return TranspositionTable { nodes: HashMap::new() };
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.nodes.len();
+    }
+}
+
+// Returns the value of the position from the perspective of the player to move.
+pub fn mcts_connect_four(
+    node: Rc<RefCell<ConnectFourState>>,
+    game: &mut ConnectFourGame,
+    model: &ConnectFourModel,
+    config: &SearchConfig,
+    table: &mut TranspositionTable,
+    display: bool,
+) -> f64 {
     if display {
         game.display();
     }
@@ -389,15 +1367,51 @@ pub fn mcts_connect_four(node: Rc<RefCell<ConnectFourState>>, game: &mut Connect
     node.borrow_mut().visits += 1;
 
     if node.borrow().moves.is_none() {
-        let valid_moves = game.valid_moves();
+        let result = game.result();
+
+        if result != 0 || game.valid_moves().is_empty() {
+            if display {
+                println!("Ended with result! {}", result);
+            }
+
+            let proven = result * -game.perspective;
+
+            let mut node_access = node.borrow_mut();
+
+            node_access.moves = Some(Vec::new());
+            node_access.proven = Some(proven);
+        }
+    }
 
-        let (policy, score) = model.forward(&game);
-        let policy = policy.to_device(Device::Cpu);
+    let transposition = if config.transpositions && node.borrow().moves.is_none() {
+        table.nodes.get(&game.hash).filter(|other| other.borrow().moves.is_some()).cloned()
+    } else {
+        None
+    };
+
+    // The shared children may already be solved, so the node is solved from them before selecting among them.
+    if let Some(transposition) = transposition {
+        let moves = transposition.borrow().moves.clone();
+
+        let mut node_access = node.borrow_mut();
+
+        node_access.moves = moves;
+        node_access.update_proven();
+    }
+
+    if let Some(proven) = node.borrow().proven {
+        node.borrow_mut().score_total += proven as f64;
+
+        return -proven as f64;
+    }
+
+    if node.borrow().moves.is_none() {
+        let evaluation = model.evaluate(&game);
 
         let mut moves: Vec<Rc<RefCell<ConnectFourState>>> = Vec::new();
 
-        for valid_move in valid_moves {
-            let mut child = ConnectFourState::new(Some(node.clone()), policy.get(valid_move).double_value(&[]));
+        for valid_move in game.valid_moves() {
+            let mut child = ConnectFourState::new(evaluation.policy[valid_move as usize]);
 
             child.game_move = Some(valid_move);
 
@@ -405,70 +1419,363 @@ pub fn mcts_connect_four(node: Rc<RefCell<ConnectFourState>>, game: &mut Connect
         }
 
         node.borrow_mut().moves = Some(moves);
+
+        if config.transpositions {
+            table.nodes.insert(game.hash, node.clone());
+        }
+
+        if display {
+            println!("Evaluated leaf {}", (evaluation.value * 100f64).floor() / 100f64);
+        }
+
+        node.borrow_mut().score_total -= evaluation.value;
+
+        return evaluation.value;
     }
 
-    let result = game.result();
+    let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
+    let mut best_score = 0f64;
 
-    if result == 0 && node.borrow().moves.as_ref().unwrap().len() > 0 {
-        let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
-        let mut best_score = 0f64;
+    let visits = node.borrow().visits;
+    let priors = node.borrow().priors();
 
-        for game_move in node.borrow().moves.as_ref().unwrap() {
-            let game_move_access = game_move.borrow();
+    for (game_move, prior) in node.borrow().moves.as_ref().unwrap().iter().zip(priors) {
+        let game_move_access = game_move.borrow();
 
-            let score = game_move_access.get_score();
+        if game_move_access.proven == Some(-1) {
+            continue;
+        }
 
-            if best_move.is_none() || score > best_score {
-                best_move = Some(game_move.clone());
-                best_score = score;
-            }
+        let score = game_move_access.get_score(prior, visits, config);
 
-            if display {
-                if game_move_access.visits > 0 {
-                    println!(
-                        "Move {} {} {} {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        ((game_move_access.score_total as f64) / (game_move_access.visits as f64) * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
-                } else {
-                    println!(
-                        "Move {} {} no visits {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
+        if best_move.is_none() || score > best_score {
+            best_move = Some(game_move.clone());
+            best_score = score;
+        }
+    }
+
+    // Only reachable when every reply is lost for its mover, which `update_proven` turns into a win for this node.
+    let Some(best_move) = best_move else {
+        let mut node_access = node.borrow_mut();
+
+        node_access.update_proven();
+
+        let proven = node_access.proven.unwrap_or(1);
+
+        node_access.score_total += proven as f64;
+
+        return -proven as f64;
+    };
+
+    if display {
+        println!("{}", node.borrow().search_result());
+        println!("Exploring {}", best_move.borrow().game_move.unwrap());
+    }
+
+    return visit_child(node, best_move, game, model, config, table, display);
+}
+
+fn visit_child(
+    node: Rc<RefCell<ConnectFourState>>,
+    child: Rc<RefCell<ConnectFourState>>,
+    game: &mut ConnectFourGame,
+    model: &ConnectFourModel,
+    config: &SearchConfig,
+    table: &mut TranspositionTable,
+    display: bool,
+) -> f64 {
+    game.make_move(child.borrow().game_move.unwrap());
+
+    let mut value = -mcts_connect_four(child, game, model, config, table, display);
+
+    game.undo_move();
+
+    let mut node_access = node.borrow_mut();
+
+    node_access.update_proven();
+
+    if let Some(proven) = node_access.proven {
+        value = -proven as f64;
+    }
+
+    node_access.score_total -= value;
+
+    return value;
+}
+
+pub fn search_connect_four(
+    node: Rc<RefCell<ConnectFourState>>,
+    game: &mut ConnectFourGame,
+    model: &ConnectFourModel,
+    config: &SearchConfig,
+    table: &mut TranspositionTable,
+) -> SearchResult {
+    let start = Instant::now();
+    let mut simulations = 0;
+
+    if node.borrow().moves.is_none() {
+        mcts_connect_four(node.clone(), game, model, config, table, false);
+
+        simulations += 1;
+    }
+
+    if config.dirichlet_fraction > 0f64 {
+        add_dirichlet_noise(&node, config);
+    }
+
+    if let RootSelection::SequentialHalving {
+        considered_moves,
+        c_visit,
+        c_scale,
+    } = config.root_selection
+    {
+        sequential_halving(
+            node.clone(),
+            game,
+            model,
+            config,
+            table,
+            start,
+            simulations,
+            considered_moves,
+            c_visit,
+            c_scale,
+        );
+
+        return node.borrow().search_result();
+    }
+
+    while !config.finished(start, simulations) && node.borrow().proven.is_none() {
+        mcts_connect_four(node.clone(), game, model, config, table, false);
+
+        simulations += 1;
+    }
+
+    return node.borrow().search_result();
+}
+
+// Gumbel AlphaZero root search (Danihelka et al. 2022). The simulation budget is split over phases that each halve the
+// set of candidate moves, sampled without replacement with Gumbel-top-k. Phases are planned over `config.simulations`,
+// a time budget ends the search after the phase in which it runs out. `simulations` already spent on the root count
+// against the budget.
+#[allow(clippy::too_many_arguments)]
+fn sequential_halving(
+    node: Rc<RefCell<ConnectFourState>>,
+    game: &mut ConnectFourGame,
+    model: &ConnectFourModel,
+    config: &SearchConfig,
+    table: &mut TranspositionTable,
+    start: Instant,
+    mut simulations: usize,
+    considered_moves: usize,
+    c_visit: f64,
+    c_scale: f64,
+) {
+    let children = node.borrow().moves.clone().unwrap();
+
+    if children.is_empty() {
+        return;
+    }
+
+    let priors = node.borrow().priors();
+    let logits: Vec<f64> = priors.iter().map(|prior| prior.max(1e-12).ln()).collect();
+
+    let mut rng = rand::rng();
+    let gumbel_distribution = Gumbel::new(0f64, 1f64).unwrap();
+    let gumbel: Vec<f64> = children.iter().map(|_| gumbel_distribution.sample(&mut rng)).collect();
+
+    let mut remaining: Vec<usize> = (0..children.len()).collect();
+    remaining.sort_by(|&a, &b| (gumbel[b] + logits[b]).total_cmp(&(gumbel[a] + logits[a])));
+    remaining.truncate(considered_moves.clamp(1, children.len()));
+
+    let phases = (remaining.len() as f64).log2().ceil().max(1f64) as usize;
+
+    let network_value = model.evaluate(game).value;
+
+    for _ in 0..phases {
+        if node.borrow().proven.is_some() {
+            break;
+        }
+
+        let visits_per_move = (config.simulations / (phases * remaining.len())).max(1);
+
+        for &index in &remaining {
+            for _ in 0..visits_per_move {
+                // At least one visit per move and phase can overrun small budgets, later phases then get fewer visits.
+                if simulations >= config.simulations {
+                    break;
                 }
+
+                node.borrow_mut().visits += 1;
+
+                visit_child(node.clone(), children[index].clone(), game, model, config, table, false);
+
+                simulations += 1;
             }
         }
 
-        if display {
-            println!("Exploring {}", best_move.as_ref().unwrap().borrow().game_move.unwrap());
+        if remaining.len() > 1 {
+            let scores = gumbel_scores(&children, &priors, &logits, &gumbel, network_value, c_visit, c_scale);
+
+            remaining.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            remaining.truncate(remaining.len().div_ceil(2));
         }
 
-        game.make_move(best_move.as_ref().unwrap().borrow().game_move.unwrap());
+        if config.time_budget.is_some_and(|budget| start.elapsed() >= budget) {
+            break;
+        }
+    }
+
+    let scores = gumbel_scores(&children, &priors, &logits, &gumbel, network_value, c_visit, c_scale);
+    let selected = *remaining.iter().max_by(|&&a, &&b| scores[a].total_cmp(&scores[b])).unwrap();
+
+    let no_gumbel = vec![0f64; children.len()];
+    let improved_logits = gumbel_scores(&children, &priors, &logits, &no_gumbel, network_value, c_visit, c_scale);
 
-        let result = mcts_connect_four(best_move.unwrap().clone(), game, model, display);
+    let max_logit = improved_logits.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let exponentials: Vec<f64> = improved_logits.iter().map(|logit| (logit - max_logit).exp()).collect();
+    let total: f64 = exponentials.iter().sum();
 
-        game.undo_move();
+    let mut node_access = node.borrow_mut();
 
-        {
-            node.borrow_mut().score_total += result * -game.perspective;
+    node_access.selected_move = children[selected].borrow().game_move;
+    node_access.policy_target = Some(exponentials.iter().map(|exponential| exponential / total).collect());
+}
+
+// g(a) + logits(a) + sigma(completed Q(a)), unvisited moves are completed with the mixed value estimate.
+fn gumbel_scores(
+    children: &[Rc<RefCell<ConnectFourState>>],
+    priors: &[f64],
+    logits: &[f64],
+    gumbel: &[f64],
+    network_value: f64,
+    c_visit: f64,
+    c_scale: f64,
+) -> Vec<f64> {
+    let mut total_visits = 0f64;
+    let mut max_visits = 0f64;
+    let mut visited_policy = 0f64;
+    let mut visited_q = 0f64;
+
+    for (child, prior) in children.iter().zip(priors) {
+        let child = child.borrow();
+
+        if child.visits > 0 {
+            total_visits += child.visits as f64;
+            max_visits = max_visits.max(child.visits as f64);
+            visited_policy += prior;
+            visited_q += prior * child.score_total / child.visits as f64;
         }
+    }
 
-        return result;
+    let mixed_value = if visited_policy > 0f64 {
+        (network_value + total_visits * visited_q / visited_policy) / (1f64 + total_visits)
     } else {
-        if display {
-            println!("Ended with result! {}", result);
-        }
+        network_value
+    };
+
+    return children
+        .iter()
+        .enumerate()
+        .map(|(index, child)| {
+            let child = child.borrow();
+
+            let q = if child.visits > 0 {
+                child.score_total / child.visits as f64
+            } else {
+                mixed_value
+            };
+
+            gumbel[index] + logits[index] + (c_visit + max_visits) * c_scale * (q + 1f64) / 2f64
+        })
+        .collect();
+}
 
-        {
-            node.borrow_mut().score_total += result * -game.perspective;
+pub fn advance_root(
+    node: Rc<RefCell<ConnectFourState>>,
+    game_move: i64,
+    config: &SearchConfig,
+    table: &mut TranspositionTable,
+) -> Rc<RefCell<ConnectFourState>> {
+    if config.reuse_tree {
+        if let Some(child) = node.borrow().child(game_move) {
+            return child;
         }
+    }
 
-        return result;
+    table.clear();
+
+    let mut root = ConnectFourState::new(0f64);
+
+    root.game_move = Some(game_move);
+
+    return Rc::new(RefCell::new(root));
+}
+
+// Mixes fresh noise into the network priors of the root, replacing the noise of any earlier search from it.
+fn add_dirichlet_noise(node: &Rc<RefCell<ConnectFourState>>, config: &SearchConfig) {
+    let mut node = node.borrow_mut();
+
+    node.noisy_policy = None;
+
+    let priors = node.priors();
+
+    if priors.is_empty() {
+        return;
     }
+
+    let mut rng = rand::rng();
+    let gamma = Gamma::new(config.dirichlet_alpha, 1f64).unwrap();
+
+    let noise: Vec<f64> = priors.iter().map(|_| gamma.sample(&mut rng)).collect();
+    let noise_total: f64 = noise.iter().sum();
+
+    node.noisy_policy = Some(
+        priors
+            .iter()
+            .zip(noise)
+            .map(|(prior, noise)| (1f64 - config.dirichlet_fraction) * prior + config.dirichlet_fraction * noise / noise_total)
+            .collect(),
+    );
+}
+
+pub fn select_move(node: &Rc<RefCell<ConnectFourState>>, config: &SearchConfig) -> i64 {
+    let node = node.borrow();
+    let moves = node.moves.as_ref().unwrap();
+
+    if let Some(winning_move) = moves.iter().find(|game_move| game_move.borrow().proven == Some(1)) {
+        return winning_move.borrow().game_move.unwrap();
+    }
+
+    if let Some(selected_move) = node.selected_move {
+        return selected_move;
+    }
+
+    if config.temperature > 0f64 {
+        let weights = moves.iter().map(|game_move| {
+            let game_move = game_move.borrow();
+
+            if game_move.proven == Some(-1) {
+                return 0f64;
+            }
+
+            (game_move.visits as f64).powf(1f64 / config.temperature)
+        });
+
+        if let Ok(distribution) = WeightedIndex::new(weights) {
+            return moves[distribution.sample(&mut rand::rng())].borrow().game_move.unwrap();
+        }
+    }
+
+    let best_move = moves
+        .iter()
+        .rev()
+        .max_by_key(|game_move| {
+            let game_move = game_move.borrow();
+
+            (game_move.proven != Some(-1), game_move.visits)
+        })
+        .unwrap();
+
+    return best_move.borrow().game_move.unwrap();
 }
@@ -0,0 +1,33 @@
+pub trait Game {
+    fn current_player(&self) -> usize;
+
+    fn is_terminal(&self) -> bool;
+
+    // Final return for `player`, 1 for a win, -1 for a loss and 0 for a draw or a position that is not terminal.
+    fn returns(&self, player: usize) -> f64;
+
+    // Factor the stakes of a finished game were multiplied by, such as the doublings for bombs and spring in Dou Dizhu.
+    // None for games without one.
+    fn score_multiplier(&self) -> Option<f64> {
+        None
+    }
+
+    // Number of symmetries of the game including the identity, used to augment training data.
+    fn symmetry_count() -> usize
+    where
+        Self: Sized,
+    {
+        1
+    }
+
+    // Image of `action` under `symmetry`, symmetry 0 is the identity. Replaying the transformed actions of a game must
+    // produce the transformed positions.
+    fn transform_action(symmetry: usize, action: i64) -> i64
+    where
+        Self: Sized,
+    {
+        let _ = symmetry;
+
+        action
+    }
+}
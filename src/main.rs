@@ -1,146 +1,48 @@
+mod arena;
+mod cache;
+mod checkpoint;
 mod connect_four;
+mod game;
+mod metrics;
+mod optimizer;
+mod pipeline;
+mod reanalyse;
+mod replay;
+mod search;
+mod self_play;
+mod storage;
+mod targets;
+mod tournament;
+mod training;
+mod zobrist;
 
 use rand::{Rng, random};
 use std::cell::RefCell;
+use std::env;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
-use connect_four::{ConnectFourGame, ConnectFourModel, ConnectFourState};
-use tch::nn::{Optimizer, OptimizerConfig, VarStore};
+use connect_four::{Architecture, ConnectFourGame, ConnectFourModel, ConnectFourState, LossWeights};
+use tch::nn::OptimizerConfig;
 use tch::{Device, Kind, NewAxis, Tensor, nn, vision};
 
-use crate::connect_four::mcts_connect_four;
-
-fn play_game(
-    node: Rc<RefCell<ConnectFourState>>,
-    game: &mut ConnectFourGame,
-    model: &ConnectFourModel,
-    history: &mut Vec<Rc<RefCell<ConnectFourState>>>,
-    display: bool,
-) -> i64 {
-    if display {
-        game.display();
-    }
-
-    for _ in 0..300 {
-        mcts_connect_four(node.clone(), game, model, false);
-    }
-
-    let result = game.result();
-
-    if result == 0 && node.borrow().moves.as_ref().unwrap().len() > 0 {
-        history.push(node.clone());
-
-        let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
-        let mut best_visits = 0i64;
-
-        for game_move in node.borrow().moves.as_ref().unwrap() {
-            let game_move_access = game_move.borrow();
-
-            let visits = game_move_access.visits;
-
-            if best_move.is_none() || visits > best_visits {
-                best_move = Some(game_move.clone());
-                best_visits = visits;
-            }
-        }
-
-        game.make_move(best_move.as_ref().unwrap().borrow().game_move.unwrap());
-
-        let result = play_game(best_move.unwrap().clone(), game, model, history, display);
-
-        game.undo_move();
-
-        return result;
-    } else {
-        return result;
-    }
-}
-
-fn train(
-    result: i64,
-    game: &mut ConnectFourGame,
-    model: &ConnectFourModel,
-    history: &mut Vec<Rc<RefCell<ConnectFourState>>>,
-    optimizer: &mut Optimizer,
-    display: bool,
-) {
-    for history_node in history.iter() {
-        if history_node.borrow().game_move.is_some() {
-            game.make_move(history_node.borrow().game_move.unwrap());
-        }
-
-        if display {
-            game.display();
-
-            for game_move in history_node.borrow().moves.as_ref().unwrap() {
-                let game_move_access = game_move.borrow();
-                let score = game_move_access.get_score();
-
-                if game_move_access.visits > 0 {
-                    println!(
-                        "Move {} {} {} {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        ((game_move_access.score_total as f64) / (game_move_access.visits as f64) * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
-                } else {
-                    println!(
-                        "Move {} {} no visits {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
-                }
-            }
-        }
-
-        if history_node.borrow().moves.as_ref().unwrap().len() == 0 {
-            continue;
-        }
-
-        let (policy, score) = model.forward(&game);
-
-        let mut target_policy: [f32; 7] = [0f32; 7];
-
-        for game_move in history_node.borrow().moves.as_ref().unwrap() {
-            target_policy[game_move.borrow().game_move.unwrap() as usize] = game_move.borrow().visits as f32;
-        }
-
-        let target_policy = Tensor::from_slice(&target_policy).to_kind(Kind::Float);
-
-        let target_policy = (target_policy.divide(&target_policy.sum(Kind::Float))).to_device(Device::cuda_if_available());
-        let target_score = Tensor::from_slice(&[(result * game.perspective) as f32]).to_device(Device::cuda_if_available());
-
-        if display {
-            println!("{}", policy);
-            println!("{}", target_policy);
-            println!("{}", score);
-            println!("{}", target_score);
-        }
-
-        let loss = model.loss(&policy, &score, &target_policy, &target_score);
-
-        if display {
-            println!("{}", loss);
-        }
-
-        optimizer.zero_grad();
-        optimizer.backward_step(&loss);
-    }
-
-    for history_node in history.iter() {
-        if history_node.borrow().game_move.is_some() {
-            game.undo_move();
-        }
-    }
-}
-
-fn human_vs_model(model: &ConnectFourModel) {
+use crate::arena::GatingConfig;
+use crate::checkpoint::{latest_bundle, latest_checkpoint, list_runs, load_model};
+use crate::connect_four::{TranspositionTable, advance_root, search_connect_four, select_move};
+use crate::metrics::Verbosity;
+use crate::optimizer::{Algorithm, Schedule};
+use crate::pipeline::{PipelineConfig, RUNS_DIRECTORY, run_pipeline};
+use crate::replay::Sampling;
+use crate::search::{RootSelection, SearchConfig};
+use crate::tournament::{TournamentConfig, load_participants, print_ranking, run_tournament, write_ranking_csv};
+
+// With `json` the analysis of every model move is printed as one line of JSON instead of a table.
+fn human_vs_model(model: &ConnectFourModel, config: &SearchConfig, json: bool) {
     let mut game = ConnectFourGame::new();
+    let mut state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+    let mut table = TranspositionTable::new();
 
     loop {
         game.display();
@@ -161,258 +63,288 @@ fn human_vs_model(model: &ConnectFourModel) {
             let move_position: i64 = input.trim().parse().expect("Please enter a valid number");
 
             game.make_move(move_position);
+
+            state = advance_root(state, move_position, config, &mut table);
         } else {
-            let state = Rc::new(RefCell::new(ConnectFourState::new(None, 0f64)));
+            let search_result = search_connect_four(state.clone(), &mut game, &model, config, &mut table);
 
-            for _ in 0..100 {
-                mcts_connect_four(state.clone(), &mut game, &model, false);
+            if json {
+                println!("{}", search_result.to_json());
+            } else {
+                println!("{}", search_result);
             }
 
-            let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
-            let mut best_visits = 0i64;
-
-            for game_move in state.borrow().moves.as_ref().unwrap() {
-                let game_move_access = game_move.borrow();
-
-                let score = game_move_access.get_score();
-                let visits = game_move_access.visits;
-
-                if best_move.is_none() || visits > best_visits {
-                    best_move = Some(game_move.clone());
-                    best_visits = visits;
-                }
-
-                if game_move_access.visits > 0 {
-                    println!(
-                        "Move {} {} {} {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        ((game_move_access.score_total as f64) / (game_move_access.visits as f64) * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
-                } else {
-                    println!(
-                        "Move {} {} no visits {} {}",
-                        game_move_access.game_move.unwrap(),
-                        (score * 100f64).floor() / 100f64,
-                        game_move_access.visits,
-                        (game_move_access.policy * 100f64).floor() / 100f64
-                    )
-                }
-            }
+            let (_, score) = model.forward_batch(&[&game]);
+
+            println!("Score {}", score.double_value(&[0]));
+            println!("Cache hit rate {:.2}", model.cache().hit_rate());
 
-            let (policy, score) = model.forward(&game);
+            let best_move = select_move(&state, config);
 
-            println!("Score {}", score.double_value(&[]));
+            game.make_move(best_move);
 
-            game.make_move(best_move.as_ref().unwrap().borrow().game_move.unwrap());
+            state = advance_root(state, best_move, config, &mut table);
         }
     }
 }
 
-fn model_vs_model(model_a: &ConnectFourModel, model_b: &ConnectFourModel, display: bool, random_start: bool) -> i64 {
-    let mut game = ConnectFourGame::new();
+fn overfit_test() {
+    let var_store = nn::VarStore::new(Device::cuda_if_available());
 
-    if random_start {
-        let mut rng = rand::rng();
+    let model = ConnectFourModel::new(&var_store.root());
 
-        game.make_move(rng.random_range(0..7));
-        game.make_move(rng.random_range(0..7));
-    }
+    let mut optimizer = nn::AdamW::default().build(&var_store, 1e-3).unwrap();
 
-    loop {
-        if display {
-            game.display();
-        }
+    let mut rng = rand::rng();
 
-        let result = game.result();
+    for i in 0..1000 {
+        println!("\n\nIteration > {}", i);
+        
+        let a = rng.random_range(0..7);
 
-        if result != 0 || game.valid_moves().len() == 0 {
-            if display {
-                println!("Finished game with result {}", result);
-            }
+        // println!("{}", a);
 
-            return result;
-        }
+        let mut game = ConnectFourGame::new();
+        game.make_move(a);
 
-        let state = Rc::new(RefCell::new(ConnectFourState::new(None, 0f64)));
+        let inputs = model.encode(&game).unsqueeze(0).to_device(Device::cuda_if_available());
+        let legal = model.legal_mask(&game).unsqueeze(0).to_device(Device::cuda_if_available());
 
-        let perspective = game.perspective;
+        let (log_policy, score) = model.forward_log_policy(&inputs, &legal, true);
 
-        for _ in 0..300 {
-            mcts_connect_four(state.clone(), &mut game, if perspective == 1 { model_a } else { model_b }, false);
-        }
+        let mut target_policy: [f32; 7] = [0f32; 7];
+        target_policy[a as usize] = 1f32;
 
-        let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
-        let mut best_visits = 0i64;
+        let target_policy = Tensor::from_slice(&target_policy)
+            .to_kind(Kind::Float)
+            .unsqueeze(0)
+            .to_device(Device::cuda_if_available());
 
-        for game_move in state.borrow().moves.as_ref().unwrap() {
-            let game_move_access = game_move.borrow();
+        let target_score = Tensor::from_slice(&[(if a < 3 { 1 } else if a > 3 { -1 } else { 0 }) as f32]).to_device(Device::cuda_if_available());
 
-            let visits = game_move_access.visits;
+        println!("{}", log_policy.exp());
+        println!("{}", target_policy);
+        println!("{}", score);
+        println!("{}", target_score);
 
-            if best_move.is_none() || visits > best_visits {
-                best_move = Some(game_move.clone());
-                best_visits = visits;
-            }
-        }
+        let loss = model.loss(&log_policy, &score, &target_policy, &target_score, &LossWeights::default());
 
-        let (policy, score) = if game.perspective == 1 { model_a } else { model_b }.forward(&game);
+        println!("{}", loss);
 
-        if display {
-            println!("Score {}", score.double_value(&[]));
-        }
+        optimizer.zero_grad();
+        optimizer.backward_step(&loss);
 
-        game.make_move(best_move.as_ref().unwrap().borrow().game_move.unwrap());
+        // if i % 1000 == 0 {
+        //     var_store.save("./checkpoints/test_training.ckpt").unwrap();
+        // }
     }
 }
 
-fn model_vs_model_policy(model_a: &ConnectFourModel, model_b: &ConnectFourModel, display: bool, random_start: bool) -> i64 {
-    let mut game = ConnectFourGame::new();
-
-    if random_start {
-        let mut rng = rand::rng();
+fn option_value<T: std::str::FromStr>(args: &[String], name: &str) -> Option<T> {
+    let index = args.iter().position(|arg| arg == name)?;
+    let value = args.get(index + 1)?;
 
-        game.make_move(rng.random_range(0..7));
-        game.make_move(rng.random_range(0..7));
-    }
+    return Some(value.parse().unwrap_or_else(|_| panic!("Invalid value for {}", name)));
+}
 
-    loop {
-        if display {
-            game.display();
-        }
+// Newest run directory in `RUNS_DIRECTORY` that `holds` what the command needs, `what` names it when there is none.
+fn latest_run(holds: impl Fn(&Path) -> bool, what: &str) -> PathBuf {
+    return list_runs(Path::new(RUNS_DIRECTORY))
+        .into_iter()
+        .find(|run| holds(run.as_path()))
+        .unwrap_or_else(|| panic!("No run in {} holds a {}, pass --checkpoints DIR", RUNS_DIRECTORY, what));
+}
 
-        let result = game.result();
+// The `--checkpoints` directory, by default the newest run holding a checkpoint.
+fn checkpoint_directory(args: &[String]) -> PathBuf {
+    return match option_value::<String>(args, "--checkpoints") {
+        Some(directory) => PathBuf::from(directory),
+        None => latest_run(|run| latest_checkpoint(run).is_some(), "checkpoint"),
+    };
+}
 
-        if result != 0 || game.valid_moves().len() == 0 {
-            if display {
-                println!("Finished game with result {}", result);
-            }
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-            return result;
-        }
+    match args.first().map(String::as_str) {
+        Some("train") => {
+            let mut config = PipelineConfig::default();
 
-        let state = Rc::new(RefCell::new(ConnectFourState::new(None, 0f64)));
+            if let Some(workers) = option_value(&args, "--workers") {
+                config.workers = workers;
+            }
 
-        let perspective = game.perspective;
+            config.resume = args.iter().any(|arg| arg == "--resume");
 
-        mcts_connect_four(state.clone(), &mut game, if perspective == 1 { model_a } else { model_b }, false);
+            if let Some(directory) = option_value::<String>(&args, "--checkpoints") {
+                config.checkpoint_directory = PathBuf::from(directory);
+            } else if config.resume {
+                config.checkpoint_directory = latest_run(|run| latest_bundle(run).is_some(), "training bundle");
+            }
 
-        let mut best_move: Option<Rc<RefCell<ConnectFourState>>> = None;
-        let mut best_policy = 0f64;
+            if let Some(max_steps) = option_value(&args, "--steps") {
+                config.max_steps = Some(max_steps);
+            }
 
-        for game_move in state.borrow().moves.as_ref().unwrap() {
-            let game_move_access = game_move.borrow();
+            if args.iter().any(|arg| arg == "--gating") {
+                config.gating = Some(GatingConfig::default());
+            }
 
-            let policy = game_move_access.policy;
+            if let Some(blocks) = option_value(&args, "--blocks") {
+                config.architecture = Architecture::ResNet {
+                    blocks,
+                    channels: option_value(&args, "--channels").unwrap_or(64),
+                };
+            }
 
-            if display {
-                println!(
-                    "Move {} - {}",
-                    game_move_access.game_move.unwrap(),
-                    (policy * 100f64).floor() / 100f64
-                );
+            if args.iter().any(|arg| arg == "--gumbel") {
+                config.self_play = config.self_play.with_root_selection(RootSelection::sequential_halving());
             }
 
-            if best_move.is_none() || policy > best_policy {
-                best_move = Some(game_move.clone());
-                best_policy = policy;
+            if let Some(probability) = option_value(&args, "--full-search-probability") {
+                config.self_play.full_search_probability = probability;
             }
-        }
 
-        let (policy, score) = if game.perspective == 1 { model_a } else { model_b }.forward(&game);
+            if let Some(simulations) = option_value(&args, "--fast-simulations") {
+                config.self_play.fast_search.simulations = simulations;
+            }
 
-        if display {
-            println!("Score {}", score.double_value(&[]));
-        }
+            if let Some(discount) = option_value(&args, "--discount") {
+                config.self_play.value_targets.discount = discount;
+            }
 
-        game.make_move(best_move.as_ref().unwrap().borrow().game_move.unwrap());
-    }
-}
+            if let Some(bootstrap_steps) = option_value(&args, "--bootstrap") {
+                config.self_play.value_targets.bootstrap_steps = Some(bootstrap_steps);
+            }
 
-struct Participant {
-    name: String,
-    vs: nn::VarStore,
-    model: ConnectFourModel,
-    elo: f64,
-}
+            if let Some(fraction) = option_value(&args, "--reanalyse") {
+                config.reanalyse.fraction = fraction;
+            }
 
-impl Participant {
-    fn new(name: String, vs: nn::VarStore, model: ConnectFourModel) -> Participant {
-        return Participant {
-            name,
-            vs,
-            model,
-            elo: 1500f64,
-        };
-    }
-}
+            if args.iter().any(|arg| arg == "--prioritised") {
+                config.training.sampling = Sampling::Prioritised {
+                    alpha: option_value(&args, "--alpha").unwrap_or(0.6),
+                    beta: option_value(&args, "--beta").unwrap_or(0.4),
+                };
+            }
 
-fn main() {
-    // let mut var_store = nn::VarStore::new(Device::cuda_if_available());
-    // var_store.load("./checkpoints/connect_four_08400.ckpt").unwrap();
+            if args.iter().any(|arg| arg == "--sgd") {
+                config.training.algorithm = Algorithm::Sgd {
+                    momentum: option_value(&args, "--momentum").unwrap_or(0.9),
+                    nesterov: args.iter().any(|arg| arg == "--nesterov"),
+                };
+            }
 
-    // let model = ConnectFourModel::new(&var_store.root());
+            if let Some(learning_rate) = option_value(&args, "--lr") {
+                config.training.learning_rate = learning_rate;
+            }
 
-    // let mut optimizer = nn::AdamW::default().build(&var_store, 1e-3).unwrap();
-    // let mut game = ConnectFourGame::new();
+            if let Some(interval) = option_value(&args, "--lr-step") {
+                config.training.schedule = Schedule::Step {
+                    interval,
+                    factor: option_value(&args, "--lr-factor").unwrap_or(0.1),
+                };
+            } else if let Some(total_steps) = option_value(&args, "--cosine") {
+                config.training.schedule = Schedule::Cosine {
+                    total_steps,
+                    minimum: option_value(&args, "--min-lr").unwrap_or(0f64),
+                };
+            }
 
-    // for i in 8400..30000 {
-    //     println!("Iteration > {}", i);
+            if let Some(warmup_steps) = option_value(&args, "--warmup") {
+                config.training.warmup_steps = warmup_steps;
+            }
 
-    //     let state = Rc::new(RefCell::new(ConnectFourState::new(None, 0f64)));
-    //     let mut history = Vec::new();
+            if let Some(weight_decay) = option_value(&args, "--weight-decay") {
+                config.training.weight_decay = weight_decay;
+            }
 
-    //     let result = play_game(state, &mut game, &model, &mut history, false);
-    //     train(result, &mut game, &model, &mut history, &mut optimizer, i % 100 == 0);
+            if let Some(gradient_clip) = option_value(&args, "--clip") {
+                config.training.gradient_clip = Some(gradient_clip);
+            }
 
-    //     if i % 100 == 0 {
-    //         var_store.save(format!("./checkpoints/connect_four_{:05}.ckpt", i)).unwrap();
-    //     }
-    // }
+            if let Some(policy) = option_value(&args, "--policy-weight") {
+                config.training.loss_weights.policy = policy;
+            }
 
-    let var_store = nn::VarStore::new(Device::cuda_if_available());
+            if let Some(value) = option_value(&args, "--value-weight") {
+                config.training.loss_weights.value = value;
+            }
 
-    let model = ConnectFourModel::new(&var_store.root());
+            if let Some(l2) = option_value(&args, "--l2") {
+                config.training.loss_weights.l2 = l2;
+            }
 
-    let mut optimizer = nn::AdamW::default().build(&var_store, 1e-3).unwrap();
+            if let Some(auxiliary_weight) = option_value(&args, "--auxiliary") {
+                config.training.auxiliary_weight = Some(auxiliary_weight);
+            }
 
-    let mut rng = rand::rng();
+            if args.iter().any(|arg| arg == "--quiet") {
+                config.verbosity = Verbosity::Quiet;
+            } else if args.iter().any(|arg| arg == "--verbose") {
+                config.verbosity = Verbosity::Verbose;
+            }
 
-    for i in 0..1000 {
-        println!("\n\nIteration > {}", i);
-        
-        let a = rng.random_range(0..7);
+            run_pipeline(config);
+        }
+        Some("play") => {
+            let directory = checkpoint_directory(&args);
+            let (_, path) = latest_checkpoint(&directory).expect("No checkpoint to play against");
 
-        // println!("{}", a);
+            let config = SearchConfig {
+                simulations: option_value(&args, "--simulations").unwrap_or(100),
+                time_budget: option_value(&args, "--time-ms").map(Duration::from_millis),
+                ..Default::default()
+            };
 
-        let mut game = ConnectFourGame::new();
-        game.make_move(a);
+            let (_var_store, model) = load_model(&path, Device::cuda_if_available()).unwrap();
+            let model = model.with_cache(option_value(&args, "--cache").unwrap_or(100_000));
 
-        let (policy, score) = model.forward(&game);
+            human_vs_model(&model, &config, args.iter().any(|arg| arg == "--json"));
+        }
+        Some("tournament") => {
+            let directory = checkpoint_directory(&args);
+            let output = option_value::<String>(&args, "--output").unwrap_or("./tournament.csv".to_string());
 
-        let mut target_policy: [f32; 7] = [0f32; 7];
-        target_policy[a as usize] = 1f32;
+            let mut config = TournamentConfig::default();
 
-        let target_policy = Tensor::from_slice(&target_policy).to_kind(Kind::Float).to_device(Device::cuda_if_available());
+            if let Some(games) = option_value(&args, "--games") {
+                config.games_per_pair = games;
+            }
 
-        let target_score = Tensor::from_slice(&[(if a < 3 { 1 } else if a > 3 { -1 } else { 0 }) as f32]).to_device(Device::cuda_if_available());
+            if let Some(simulations) = option_value(&args, "--simulations") {
+                config.search.simulations = simulations;
+            }
 
-        println!("{}", policy);
-        println!("{}", target_policy);
-        println!("{}", score);
-        println!("{}", target_score);
+            if let Some(milliseconds) = option_value(&args, "--time-ms") {
+                config.search.time_budget = Some(Duration::from_millis(milliseconds));
+            }
 
-        let loss = model.loss(&policy, &score, &target_policy, &target_score);
+            let every = option_value(&args, "--every").unwrap_or(1000);
 
-        println!("{}", loss);
+            if every == 0 {
+                panic!("Invalid value for --every, it must be at least 1");
+            }
 
-        optimizer.zero_grad();
-        optimizer.backward_step(&loss);
+            let mut participants = load_participants(&directory, every, &config);
+            let results = run_tournament(&mut participants, &config);
 
-        // if i % 1000 == 0 {
-        //     var_store.save("./checkpoints/test_training.ckpt").unwrap();
-        // }
+            print_ranking(&participants, &results);
+            write_ranking_csv(Path::new(&output), &participants, &results).unwrap();
+        }
+        Some("overfit-test") => overfit_test(),
+        _ => {
+            println!("Usage: alpha_dou_dizhu <command>");
+            println!("Checkpoint directories default to a new ./runs/<time> for train and to the newest run for the others.");
+            println!("  train [--workers N] [--checkpoints DIR] [--steps N] [--resume] [--gating] [--gumbel]");
+            println!("        [--full-search-probability P] [--fast-simulations N] [--discount D] [--bootstrap N]");
+            println!("        [--reanalyse FRACTION] [--prioritised [--alpha A] [--beta B]] [--blocks N [--channels N]]");
+            println!("        [--sgd [--momentum M] [--nesterov]] [--lr RATE] [--lr-step N [--lr-factor F] | --cosine N [--min-lr RATE]]");
+            println!("        [--warmup N] [--weight-decay D] [--clip NORM] [--auxiliary WEIGHT]");
+            println!("        [--policy-weight W] [--value-weight W] [--l2 W] [--quiet | --verbose]");
+            println!("  play [--checkpoints DIR] [--simulations N] [--time-ms N] [--cache N] [--json]");
+            println!("  tournament [--checkpoints DIR] [--every N] [--games N] [--simulations N] [--time-ms N] [--output FILE]");
+            println!("  overfit-test");
+        }
     }
 }
@@ -0,0 +1,192 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+use tch::{Kind, Tensor};
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    // Prints a summary every checkpoint.
+    Normal,
+    // Prints the metrics of every training step.
+    Verbose,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct TrainingMetrics {
+    pub loss: f64,
+    // Policy cross entropy, averaged over the samples that have a policy target.
+    pub policy_loss: f64,
+    pub value_loss: f64,
+    pub policy_entropy: f64,
+    // KL divergence from the policy target to the predicted policy.
+    pub policy_kl: f64,
+    // Fraction of decided value targets whose sign the value head predicts correctly.
+    pub value_accuracy: f64,
+    pub learning_rate: f64,
+    // Global gradient norm before clipping.
+    pub gradient_norm: f64,
+}
+
+impl TrainingMetrics {
+    // Fills in the policy and value statistics for a batch of outputs of `forward_log_policy` and their targets.
+    pub fn measure(&mut self, log_policy: &Tensor, score: &Tensor, target_policy: &Tensor, target_score: &Tensor) {
+        tch::no_grad(|| {
+            let has_policy = target_policy.sum_dim_intlist(1, false, Kind::Float).gt(0f64).to_kind(Kind::Float);
+            let policy_count = has_policy.sum(Kind::Float).double_value(&[]).max(1f64);
+
+            let log_target = target_policy.clamp_min(1e-12).log();
+
+            let cross_entropy = -(target_policy * log_policy).sum_dim_intlist(1, false, Kind::Float);
+            let target_entropy = -(target_policy * log_target).sum_dim_intlist(1, false, Kind::Float);
+
+            self.policy_loss = (&cross_entropy * &has_policy).sum(Kind::Float).double_value(&[]) / policy_count;
+            self.policy_kl = ((&cross_entropy - target_entropy) * &has_policy).sum(Kind::Float).double_value(&[]) / policy_count;
+            self.policy_entropy = -(log_policy.exp() * log_policy)
+                .sum_dim_intlist(1, false, Kind::Float)
+                .mean(Kind::Float)
+                .double_value(&[]);
+
+            self.value_loss = (score - target_score).pow_tensor_scalar(2).mean(Kind::Float).double_value(&[]);
+
+            let decided = target_score.abs().gt(1e-6).to_kind(Kind::Float);
+            let correct = (score * target_score).gt(0f64).to_kind(Kind::Float);
+
+            self.value_accuracy =
+                (correct * &decided).sum(Kind::Float).double_value(&[]) / decided.sum(Kind::Float).double_value(&[]).max(1f64);
+        });
+    }
+}
+
+// Appends one row of metrics per training step to a CSV file, so a resumed run continues the same file.
+pub struct MetricsLog {
+    writer: BufWriter<File>,
+}
+
+impl MetricsLog {
+    // Rows after `step`, the step the run starts from, were written after the bundle it resumes and are dropped as those
+    // steps are trained again.
+    pub fn new(path: &Path, step: usize) -> io::Result<MetricsLog> {
+        let kept: String = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| {
+                    line.split(',')
+                        .next()
+                        .and_then(|row_step| row_step.parse::<usize>().ok())
+                        .is_none_or(|row_step| row_step <= step)
+                })
+                .map(|line| format!("{}\n", line))
+                .collect(),
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        fs::write(path, kept)?;
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+
+        let mut writer = BufWriter::new(file);
+
+        if empty {
+            writeln!(
+                writer,
+                "step,games,games_per_second,simulations_per_second,loss,policy_loss,value_loss,policy_entropy,policy_kl,value_accuracy,learning_rate,gradient_norm"
+            )?;
+        }
+
+        return Ok(MetricsLog { writer });
+    }
+
+    pub fn write(
+        &mut self,
+        step: usize,
+        games: usize,
+        games_per_second: f64,
+        simulations_per_second: f64,
+        metrics: &TrainingMetrics,
+    ) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{},{},{:.3},{:.1},{:.6},{:.6},{:.6},{:.6},{:.6},{:.4},{:e},{:.6}",
+            step,
+            games,
+            games_per_second,
+            simulations_per_second,
+            metrics.loss,
+            metrics.policy_loss,
+            metrics.value_loss,
+            metrics.policy_entropy,
+            metrics.policy_kl,
+            metrics.value_accuracy,
+            metrics.learning_rate,
+            metrics.gradient_norm
+        )?;
+
+        return self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tch::Tensor;
+
+    use crate::metrics::{MetricsLog, TrainingMetrics};
+
+    #[test]
+    fn test_measure() {
+        // The second sample has no policy target and the value head gets its sign wrong.
+        let log_policy = Tensor::from_slice(&[0.5f32, 0.5f32, 0.25f32, 0.75f32]).view([2, 2]).log();
+        let target_policy = Tensor::from_slice(&[1f32, 0f32, 0f32, 0f32]).view([2, 2]);
+        let score = Tensor::from_slice(&[0.5f32, 0.2f32]);
+        let target_score = Tensor::from_slice(&[1f32, -1f32]);
+
+        let mut metrics = TrainingMetrics::default();
+
+        metrics.measure(&log_policy, &score, &target_policy, &target_score);
+
+        let entropy = (2f64.ln() - 0.25 * 0.25f64.ln() - 0.75 * 0.75f64.ln()) / 2f64;
+
+        assert!((metrics.policy_loss - 2f64.ln()).abs() < 1e-5);
+        assert!((metrics.policy_kl - 2f64.ln()).abs() < 1e-5);
+        assert!((metrics.policy_entropy - entropy).abs() < 1e-5);
+        assert!((metrics.value_loss - (0.25 + 1.44) / 2f64).abs() < 1e-5);
+        assert_eq!(metrics.value_accuracy, 0.5);
+    }
+
+    #[test]
+    fn test_resume_truncates() {
+        let path = std::env::temp_dir().join(format!("alpha_dou_dizhu_metrics_{}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut log = MetricsLog::new(&path, 0).unwrap();
+
+        for step in 1..=3 {
+            log.write(step, step, 1f64, 1f64, &TrainingMetrics::default()).unwrap();
+        }
+
+        drop(log);
+
+        let mut log = MetricsLog::new(&path, 2).unwrap();
+
+        log.write(3, 4, 1f64, 1f64, &TrainingMetrics::default()).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let rows: Vec<(&str, &str)> = contents
+            .lines()
+            .map(|line| {
+                let mut fields = line.split(',');
+
+                (fields.next().unwrap(), fields.next().unwrap())
+            })
+            .collect();
+
+        assert_eq!(rows, vec![("step", "games"), ("1", "1"), ("2", "2"), ("3", "4")]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use tch::nn::VarStore;
+use tch::{Kind, TchError, Tensor};
+
+#[derive(Clone, Copy)]
+pub enum Algorithm {
+    // AdamW (Loshchilov and Hutter 2019), weight decay is decoupled from the gradient.
+    AdamW { beta1: f64, beta2: f64, eps: f64 },
+    // SGD with momentum as in AlphaZero, weight decay is added to the gradient as an L2 penalty.
+    Sgd { momentum: f64, nesterov: bool },
+}
+
+#[derive(Clone, Copy)]
+pub enum Schedule {
+    Constant,
+    // Multiplies the learning rate by `factor` every `interval` steps.
+    Step { interval: usize, factor: f64 },
+    // Anneals from the base learning rate to `minimum` over `total_steps`, then stays at `minimum`.
+    Cosine { total_steps: usize, minimum: f64 },
+}
+
+impl Schedule {
+    // Rejects schedules that would divide by zero, an interval or a length of 0 steps.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Schedule::Step { interval: 0, .. } => Err("step schedule interval must be at least 1".to_string()),
+            Schedule::Cosine { total_steps: 0, .. } => Err("cosine schedule total_steps must be at least 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // Learning rate at `step`, ramped up linearly over the first `warmup_steps` steps.
+    pub fn learning_rate(&self, base: f64, warmup_steps: usize, step: usize) -> f64 {
+        let learning_rate = match *self {
+            Schedule::Constant => base,
+            Schedule::Step { interval, factor } => base * factor.powi((step / interval) as i32),
+            Schedule::Cosine { total_steps, minimum } => {
+                let progress = (step as f64 / total_steps as f64).min(1f64);
+
+                minimum + (base - minimum) * (1f64 + (PI * progress).cos()) / 2f64
+            }
+        };
+
+        if step < warmup_steps {
+            return learning_rate * (step + 1) as f64 / warmup_steps as f64;
+        }
+
+        return learning_rate;
+    }
+}
+
+// Optimizer kept on the Rust side, unlike the `nn` optimizers its state can be saved and restored.
+pub struct Optimizer {
+    pub algorithm: Algorithm,
+    pub learning_rate: f64,
+    pub weight_decay: f64,
+
+    // Optimizer steps taken, used for bias correction.
+    pub steps: i64,
+
+    variables: Vec<(String, Tensor)>,
+    // Exponential moving averages of the gradient and squared gradient for AdamW, the momentum buffer and nothing for SGD.
+    first_moments: Vec<Tensor>,
+    second_moments: Vec<Tensor>,
+}
+
+impl Optimizer {
+    pub fn new(var_store: &VarStore, algorithm: Algorithm, learning_rate: f64, weight_decay: f64) -> Optimizer {
+        // Buffers such as batch normalisation statistics are neither optimised nor decayed.
+        let mut variables: Vec<(String, Tensor)> = var_store
+            .variables()
+            .into_iter()
+            .filter(|(_, variable)| variable.requires_grad())
+            .collect();
+
+        variables.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let first_moments = variables.iter().map(|(_, variable)| variable.zeros_like()).collect();
+        let second_moments = variables.iter().map(|(_, variable)| variable.zeros_like()).collect();
+
+        return Optimizer {
+            algorithm,
+            learning_rate,
+            weight_decay,
+            steps: 0,
+            variables,
+            first_moments,
+            second_moments,
+        };
+    }
+
+    pub fn zero_grad(&mut self) {
+        for (_, variable) in self.variables.iter_mut() {
+            variable.zero_grad();
+        }
+    }
+
+    // Scales the gradients so their global norm is at most `max_norm`, returns the norm before clipping.
+    pub fn clip_grad_norm(&mut self, max_norm: f64) -> f64 {
+        return tch::no_grad(|| {
+            let norm = self
+                .variables
+                .iter()
+                .map(|(_, variable)| variable.grad())
+                .filter(|grad| grad.defined())
+                .map(|grad| grad.pow_tensor_scalar(2).sum(Kind::Double).double_value(&[]))
+                .sum::<f64>()
+                .sqrt();
+
+            if norm > max_norm {
+                for (_, variable) in self.variables.iter() {
+                    let mut grad = variable.grad();
+
+                    if grad.defined() {
+                        let _ = grad.g_mul_scalar_(max_norm / (norm + 1e-6));
+                    }
+                }
+            }
+
+            norm
+        });
+    }
+
+    pub fn step(&mut self) {
+        self.steps += 1;
+
+        tch::no_grad(|| {
+            for (i, (_, variable)) in self.variables.iter_mut().enumerate() {
+                let grad = variable.grad();
+
+                if !grad.defined() {
+                    continue;
+                }
+
+                let updated = match self.algorithm {
+                    Algorithm::AdamW { beta1, beta2, eps } => {
+                        let first_correction = 1f64 - beta1.powi(self.steps as i32);
+                        let second_correction = 1f64 - beta2.powi(self.steps as i32);
+
+                        let first_moment = &self.first_moments[i] * beta1 + &grad * (1f64 - beta1);
+                        let second_moment = &self.second_moments[i] * beta2 + (&grad * &grad) * (1f64 - beta2);
+
+                        let update = (&first_moment / first_correction) / ((&second_moment / second_correction).sqrt() + eps);
+
+                        self.first_moments[i].copy_(&first_moment);
+                        self.second_moments[i].copy_(&second_moment);
+
+                        &*variable * (1f64 - self.learning_rate * self.weight_decay) - update * self.learning_rate
+                    }
+                    Algorithm::Sgd { momentum, nesterov } => {
+                        let grad = &grad + &*variable * self.weight_decay;
+                        let buffer = &self.first_moments[i] * momentum + &grad;
+
+                        let update = if nesterov {
+                            &grad + &buffer * momentum
+                        } else {
+                            buffer.shallow_clone()
+                        };
+
+                        self.first_moments[i].copy_(&buffer);
+
+                        &*variable - update * self.learning_rate
+                    }
+                };
+
+                variable.copy_(&updated);
+            }
+        });
+    }
+
+    // Sum of the squares of the trainable parameters, for an explicit L2 term in the loss.
+    pub fn squared_norm(&self) -> Tensor {
+        let squares: Vec<Tensor> = self
+            .variables
+            .iter()
+            .map(|(_, variable)| variable.pow_tensor_scalar(2).sum(Kind::Float))
+            .collect();
+
+        return Tensor::stack(&squares, 0).sum(Kind::Float);
+    }
+
+    pub fn backward_step(&mut self, loss: &Tensor) {
+        self.zero_grad();
+        loss.backward();
+        self.step();
+    }
+
+    // Named tensors holding the moments and the step count, for saving alongside the weights.
+    pub fn state(&self) -> Vec<(String, Tensor)> {
+        let mut state = vec![("steps".to_string(), Tensor::from_slice(&[self.steps]))];
+
+        for (i, (name, _)) in self.variables.iter().enumerate() {
+            state.push((format!("first_moment.{}", name), self.first_moments[i].shallow_clone()));
+            state.push((format!("second_moment.{}", name), self.second_moments[i].shallow_clone()));
+        }
+
+        return state;
+    }
+
+    pub fn load_state(&mut self, state: &HashMap<String, Tensor>) -> Result<(), TchError> {
+        let missing = |name: &str| TchError::TensorNameNotFound(name.to_string(), "optimizer state".to_string());
+
+        self.steps = state.get("steps").ok_or_else(|| missing("steps"))?.int64_value(&[0]);
+
+        tch::no_grad(|| {
+            for (i, (name, _)) in self.variables.iter().enumerate() {
+                let first_name = format!("first_moment.{}", name);
+                let second_name = format!("second_moment.{}", name);
+
+                let first_moment = state.get(&first_name).ok_or_else(|| missing(&first_name))?;
+                let second_moment = state.get(&second_name).ok_or_else(|| missing(&second_name))?;
+
+                self.first_moments[i].f_copy_(&first_moment.to_kind(Kind::Float))?;
+                self.second_moments[i].f_copy_(&second_moment.to_kind(Kind::Float))?;
+            }
+
+            return Ok(());
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tch::nn::VarStore;
+    use tch::{Device, Kind, Tensor};
+
+    use crate::optimizer::{Algorithm, Optimizer, Schedule};
+
+    #[test]
+    fn test_state() {
+        let var_store = VarStore::new(Device::Cpu);
+        let x = var_store.root().var("x", &[2], tch::nn::Init::Const(1f64));
+
+        let mut optimizer = Optimizer::new(
+            &var_store,
+            Algorithm::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+            },
+            0.1,
+            0.01,
+        );
+
+        for _ in 0..10 {
+            optimizer.backward_step(&(&x * &x).sum(Kind::Float));
+        }
+
+        assert!(x.double_value(&[0]) < 1f64);
+
+        let state: HashMap<String, Tensor> = optimizer.state().into_iter().collect();
+
+        let mut restored = Optimizer::new(
+            &var_store,
+            Algorithm::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+            },
+            0.1,
+            0.01,
+        );
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.steps, 10);
+        assert_eq!(restored.state()[1].1, optimizer.state()[1].1);
+    }
+
+    #[test]
+    fn test_squared_norm() {
+        let var_store = VarStore::new(Device::Cpu);
+        let _x = var_store.root().var("x", &[2], tch::nn::Init::Const(1f64));
+        let _buffer = var_store.root().ones_no_train("buffer", &[3]);
+
+        let optimizer = Optimizer::new(
+            &var_store,
+            Algorithm::Sgd {
+                momentum: 0f64,
+                nesterov: false,
+            },
+            0.1,
+            0f64,
+        );
+
+        assert_eq!(optimizer.squared_norm().double_value(&[]), 2f64);
+        assert_eq!(optimizer.state().len(), 3);
+    }
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(Schedule::Constant.learning_rate(1f64, 4, 1), 0.5);
+        assert_eq!(Schedule::Constant.learning_rate(1f64, 4, 10), 1f64);
+
+        let step = Schedule::Step { interval: 10, factor: 0.1 };
+
+        assert_eq!(step.learning_rate(1f64, 0, 9), 1f64);
+        assert!((step.learning_rate(1f64, 0, 25) - 0.01).abs() < 1e-12);
+
+        let cosine = Schedule::Cosine {
+            total_steps: 100,
+            minimum: 0.1,
+        };
+
+        assert_eq!(cosine.learning_rate(1f64, 0, 0), 1f64);
+        assert!((cosine.learning_rate(1f64, 0, 50) - 0.55).abs() < 1e-12);
+        assert_eq!(cosine.learning_rate(1f64, 0, 200), 0.1);
+
+        assert!(step.validate().is_ok());
+        assert!(cosine.validate().is_ok());
+        assert!(Schedule::Step { interval: 0, factor: 0.1 }.validate().is_err());
+        assert!(
+            Schedule::Cosine {
+                total_steps: 0,
+                minimum: 0.1
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let var_store = VarStore::new(Device::Cpu);
+        let x = var_store.root().var("x", &[2], tch::nn::Init::Const(1f64));
+
+        let mut optimizer = Optimizer::new(
+            &var_store,
+            Algorithm::Sgd {
+                momentum: 0.9,
+                nesterov: false,
+            },
+            0.1,
+            0f64,
+        );
+
+        optimizer.zero_grad();
+        (&x * 3f64).sum(Kind::Float).backward();
+
+        assert!((optimizer.clip_grad_norm(1f64) - 18f64.sqrt()).abs() < 1e-5);
+        assert!((optimizer.clip_grad_norm(1f64) - 1f64).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,506 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use tch::Device;
+use tch::nn;
+
+use crate::arena::{GatingConfig, play_match};
+use crate::checkpoint::{
+    CheckpointHeader, bundle_path, checkpoint_path, latest_bundle, latest_checkpoint, load_bundle, load_weights, read_header, save_bundle,
+};
+use crate::connect_four::{Architecture, ConnectFourModel};
+use crate::metrics::{MetricsLog, Verbosity};
+use crate::optimizer::Optimizer;
+use crate::reanalyse::{ReanalyseConfig, reanalyse};
+use crate::replay::ReplayBuffer;
+use crate::self_play::{SelfPlayConfig, SelfPlayGame, self_play_game};
+use crate::storage::{GameMetadata, GameWriter, load_recent_samples};
+use crate::training::{TrainingConfig, train_step};
+
+// Default parent of the checkpoint directories of training runs, which `play` and `tournament` also read from.
+pub const RUNS_DIRECTORY: &str = "./runs";
+
+#[derive(Clone)]
+pub struct PipelineConfig {
+    pub architecture: Architecture,
+    pub device: Device,
+    pub workers: usize,
+    pub checkpoint_directory: PathBuf,
+    // Training steps between published checkpoints.
+    pub checkpoint_interval: usize,
+    // Samples collected before the first training step.
+    pub min_buffer_size: usize,
+    pub max_steps: Option<usize>,
+    // Continue from the latest training bundle in the checkpoint directory, which must hold one.
+    pub resume: bool,
+    // Self-play follows only candidates that beat the best network so far, None publishes every checkpoint.
+    pub gating: Option<GatingConfig>,
+    pub verbosity: Verbosity,
+    // Games per shard in the `games` directory next to the checkpoints.
+    pub games_per_shard: usize,
+    // Network evaluations cached by every model of the pipeline, cleared whenever its weights change.
+    pub cache_capacity: usize,
+
+    pub self_play: SelfPlayConfig,
+    pub training: TrainingConfig,
+    pub reanalyse: ReanalyseConfig,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            architecture: Architecture::Mlp,
+            device: Device::cuda_if_available(),
+            workers: thread::available_parallelism().map_or(1, |workers| workers.get()),
+            // A new directory per run, `./checkpoints` holds the committed baseline checkpoints.
+            checkpoint_directory: Path::new(RUNS_DIRECTORY).join(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_secs())
+                    .to_string(),
+            ),
+            checkpoint_interval: 100,
+            min_buffer_size: 1_000,
+            max_steps: None,
+            resume: false,
+            gating: None,
+            verbosity: Verbosity::Normal,
+            games_per_shard: 1_000,
+            cache_capacity: 100_000,
+            self_play: SelfPlayConfig::default(),
+            training: TrainingConfig::default(),
+            reanalyse: ReanalyseConfig::default(),
+        }
+    }
+}
+
+// The most recently published checkpoint, workers reload whenever the step changes.
+type Published = Arc<RwLock<(usize, PathBuf)>>;
+
+// A model of the configured architecture, with the auxiliary heads the trainer checkpoints when they are trained.
+fn build_model(var_store: &nn::VarStore, config: &PipelineConfig) -> ConnectFourModel {
+    let model = ConnectFourModel::with_architecture(&var_store.root(), config.architecture).with_cache(config.cache_capacity);
+
+    if config.training.auxiliary_weight.is_some() {
+        return model.with_auxiliary_heads(&var_store.root());
+    }
+
+    return model;
+}
+
+fn spawn_worker(
+    id: usize,
+    config: PipelineConfig,
+    published: Published,
+    sender: Sender<(GameMetadata, SelfPlayGame)>,
+    stop: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    return thread::spawn(move || {
+        let mut var_store = nn::VarStore::new(config.device);
+        let model = build_model(&var_store, &config);
+
+        let mut loaded_step = None;
+
+        while !stop.load(Ordering::Relaxed) {
+            let (step, path) = published.read().unwrap().clone();
+
+            if loaded_step != Some(step) {
+                load_weights(&path, &mut var_store, &model).unwrap();
+                model.clear_cache();
+
+                loaded_step = Some(step);
+            }
+
+            let game = self_play_game(&model, &config.self_play, false);
+            let metadata = GameMetadata::new(step as u64, &config.self_play);
+
+            if sender.send((metadata, game)).is_err() {
+                break;
+            }
+        }
+
+        if config.verbosity >= Verbosity::Normal {
+            println!("Worker {} stopped", id);
+        }
+    });
+}
+
+fn save_checkpoint(var_store: &nn::VarStore, config: &PipelineConfig, step: usize, parent: &Option<String>) -> PathBuf {
+    let path = checkpoint_path(&config.checkpoint_directory, step);
+    let header = CheckpointHeader {
+        auxiliary: config.training.auxiliary_weight.is_some(),
+        hyperparameters: config.training.hyperparameters(),
+        ..CheckpointHeader::new(config.architecture, step, parent.clone())
+    };
+
+    crate::checkpoint::save_checkpoint(&path, var_store, &header).unwrap();
+
+    return path;
+}
+
+// Trains until `max_steps` or until every worker stopped, and returns the step of the checkpoint self-play followed last.
+pub fn run_pipeline(config: PipelineConfig) -> usize {
+    config
+        .training
+        .validate()
+        .unwrap_or_else(|message| panic!("Invalid training config: {}", message));
+
+    if config.workers == 0 {
+        panic!("Invalid pipeline config: workers must be at least 1, no games would ever be played");
+    }
+
+    fs::create_dir_all(&config.checkpoint_directory).unwrap();
+
+    let var_store = nn::VarStore::new(config.device);
+    let model = build_model(&var_store, &config);
+
+    let mut optimizer = Optimizer::new(
+        &var_store,
+        config.training.algorithm,
+        config.training.learning_rate,
+        config.training.weight_decay,
+    );
+
+    let mut step = 0;
+    let mut seed = rand::random();
+    let mut promoted_step = 0;
+
+    let bundle = if config.resume {
+        latest_bundle(&config.checkpoint_directory)
+    } else {
+        None
+    };
+
+    match &bundle {
+        Some((_, path)) => {
+            (step, seed, promoted_step) = load_bundle(path, &var_store, &mut optimizer).unwrap();
+
+            if config.verbosity >= Verbosity::Normal {
+                println!("Resuming from {}", path.display());
+            }
+        }
+        None => {
+            if config.resume {
+                panic!("No training bundle to resume in {}", config.checkpoint_directory.display());
+            }
+
+            // Only a run resuming from the directory's own bundle may rewrite its checkpoints.
+            if let Some((_, path)) = latest_checkpoint(&config.checkpoint_directory) {
+                panic!(
+                    "{} already holds checkpoints such as {}, resume from its training bundle or choose another directory",
+                    config.checkpoint_directory.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    // File name of the latest saved checkpoint, recorded as the parent of the next one. A resumed run keeps the parent of
+    // the checkpoint it rewrites.
+    let mut parent = if bundle.is_some() {
+        read_header(&checkpoint_path(&config.checkpoint_directory, step))
+            .ok()
+            .and_then(|header| header.parent)
+    } else {
+        None
+    };
+
+    let path = save_checkpoint(&var_store, &config, step, &parent);
+
+    parent = path.file_name().map(|name| name.to_string_lossy().to_string());
+
+    // The network self-play currently follows, only kept when gating. A resumed run reloads the network it had promoted.
+    let mut best_var_store = nn::VarStore::new(config.device);
+    let best_model = build_model(&best_var_store, &config);
+
+    let mut published_checkpoint = (step, path);
+
+    if config.gating.is_some() && promoted_step != step {
+        let promoted_path = checkpoint_path(&config.checkpoint_directory, promoted_step);
+
+        load_weights(&promoted_path, &mut best_var_store, &best_model).unwrap();
+
+        if config.verbosity >= Verbosity::Normal {
+            println!("Self-play follows the promoted network of step {}", promoted_step);
+        }
+
+        published_checkpoint = (promoted_step, promoted_path);
+    } else {
+        best_var_store.copy(&var_store).unwrap();
+    }
+
+    // Step of the checkpoint self-play follows, recorded in bundles.
+    let mut best_step = published_checkpoint.0;
+
+    let published = Arc::new(RwLock::new(published_checkpoint));
+
+    let (sender, receiver) = mpsc::channel::<(GameMetadata, SelfPlayGame)>();
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let workers: Vec<JoinHandle<()>> = (0..config.workers)
+        .map(|id| spawn_worker(id, config.clone(), published.clone(), sender.clone(), stop.clone()))
+        .collect();
+
+    drop(sender);
+
+    let games_directory = config.checkpoint_directory.join("games");
+
+    let mut buffer = ReplayBuffer::new(config.training.replay_capacity, config.training.sampling);
+
+    let (samples, skipped) = load_recent_samples(&games_directory, config.training.replay_capacity).unwrap();
+
+    if config.verbosity >= Verbosity::Normal {
+        for (shard, error) in skipped {
+            println!("Skipping unreadable shard {}: {}", shard.display(), error);
+        }
+    }
+
+    buffer.extend(samples);
+
+    if buffer.len() > 0 && config.verbosity >= Verbosity::Normal {
+        println!("Restored {} samples from {}", buffer.len(), games_directory.display());
+    }
+
+    let mut writer = GameWriter::new(&games_directory, config.games_per_shard).unwrap();
+
+    // Derived from the seed and the step so a resumed run does not replay the minibatches it already trained on.
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(step as u64));
+
+    let mut metrics_log = MetricsLog::new(&config.checkpoint_directory.join("metrics.csv"), step).unwrap();
+
+    let start = Instant::now();
+    let mut games = 0;
+    let mut simulations = 0;
+    let mut steps_taken = 0;
+
+    loop {
+        // Block for new games when training has caught up with self-play, so the buffer is not overfit.
+        while buffer.len() < config.min_buffer_size || steps_taken >= games * config.training.steps_per_game {
+            let Ok((metadata, game)) = receiver.recv() else {
+                return best_step;
+            };
+
+            writer.write(&metadata, &game).unwrap();
+            simulations += game.simulations;
+            buffer.extend(game.samples);
+            games += 1;
+        }
+
+        while let Ok((metadata, game)) = receiver.try_recv() {
+            writer.write(&metadata, &game).unwrap();
+            simulations += game.simulations;
+            buffer.extend(game.samples);
+            games += 1;
+        }
+
+        let reanalysed = (config.reanalyse.fraction * config.training.batch_size as f64).round() as usize;
+
+        reanalyse(&model, &mut buffer, &config.reanalyse, reanalysed, &mut rng);
+
+        let metrics = train_step(&model, &mut optimizer, &mut buffer, &config.training, &mut rng);
+
+        step += 1;
+        steps_taken += 1;
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let games_per_second = games as f64 / elapsed;
+        let simulations_per_second = simulations as f64 / elapsed;
+
+        metrics_log
+            .write(step, games, games_per_second, simulations_per_second, &metrics)
+            .unwrap();
+
+        if config.verbosity >= Verbosity::Verbose {
+            println!(
+                "Step > {} Loss > {:.4} Policy > {:.4} Value > {:.4} Entropy > {:.3} KL > {:.4} Accuracy > {:.3} LR > {:e}",
+                step,
+                metrics.loss,
+                metrics.policy_loss,
+                metrics.value_loss,
+                metrics.policy_entropy,
+                metrics.policy_kl,
+                metrics.value_accuracy,
+                metrics.learning_rate
+            );
+        }
+
+        let checkpoint_due = step % config.checkpoint_interval == 0;
+        let gating_due = config.gating.as_ref().is_some_and(|gating| step % gating.interval == 0);
+
+        if checkpoint_due || gating_due {
+            let path = save_checkpoint(&var_store, &config, step, &parent);
+
+            parent = path.file_name().map(|name| name.to_string_lossy().to_string());
+
+            match &config.gating {
+                Some(gating) if gating_due => {
+                    let result = play_match(&model, &best_model, &gating.search, gating.games);
+                    let (lower, upper) = result.confidence_interval(1.96);
+
+                    if config.verbosity >= Verbosity::Normal {
+                        println!(
+                            "Gating > Step {} Score > {:.3} [{:.3}, {:.3}] W/D/L > {}/{}/{} Cache > {:.2}",
+                            step,
+                            result.score(),
+                            lower,
+                            upper,
+                            result.wins,
+                            result.draws,
+                            result.losses,
+                            model.cache().hit_rate()
+                        );
+                    }
+
+                    if result.score() >= gating.threshold {
+                        best_var_store.copy(&var_store).unwrap();
+                        best_model.clear_cache();
+
+                        best_step = step;
+
+                        *published.write().unwrap() = (step, path);
+
+                        if config.verbosity >= Verbosity::Normal {
+                            println!("Promoted step {}", step);
+                        }
+                    }
+
+                    model.clear_cache();
+                }
+                Some(_) => {}
+                None => {
+                    *published.write().unwrap() = (step, path);
+
+                    best_step = step;
+                }
+            }
+
+            // Saved after gating so the bundle records a promotion made at the same step.
+            if checkpoint_due {
+                save_bundle(
+                    &bundle_path(&config.checkpoint_directory, step),
+                    &var_store,
+                    &optimizer,
+                    step,
+                    seed,
+                    best_step,
+                )
+                .unwrap();
+
+                if config.verbosity >= Verbosity::Normal {
+                    println!(
+                        "Step > {} Loss > {:.4} Games > {} Games/s > {:.2} Simulations/s > {:.0}",
+                        step, metrics.loss, games, games_per_second, simulations_per_second
+                    );
+                }
+            }
+        }
+
+        if config.max_steps.is_some_and(|max_steps| steps_taken >= max_steps) {
+            break;
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+
+    drop(receiver);
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    return best_step;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tch::Device;
+    use tch::nn::VarStore;
+
+    use crate::arena::GatingConfig;
+    use crate::checkpoint::{bundle_path, load_bundle};
+    use crate::connect_four::ConnectFourModel;
+    use crate::metrics::Verbosity;
+    use crate::optimizer::Optimizer;
+    use crate::pipeline::{PipelineConfig, run_pipeline};
+    use crate::search::SearchConfig;
+    use crate::self_play::SelfPlayConfig;
+    use crate::training::TrainingConfig;
+
+    fn config(directory: &Path, threshold: f64) -> PipelineConfig {
+        let search = SearchConfig {
+            simulations: 2,
+            ..Default::default()
+        };
+
+        return PipelineConfig {
+            device: Device::Cpu,
+            workers: 1,
+            checkpoint_directory: directory.to_path_buf(),
+            checkpoint_interval: 1,
+            min_buffer_size: 1,
+            gating: Some(GatingConfig {
+                interval: 2,
+                games: 2,
+                threshold,
+                search: search.clone(),
+            }),
+            verbosity: Verbosity::Quiet,
+            self_play: SelfPlayConfig {
+                full_search: search.clone(),
+                fast_search: search,
+                ..Default::default()
+            },
+            training: TrainingConfig {
+                batch_size: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_resume_after_promotion() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_pipeline_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        // Every candidate is promoted, the bundle of step 2 is saved at the step of the promotion.
+        let promoting = PipelineConfig {
+            max_steps: Some(3),
+            ..config(&directory, 0f64)
+        };
+
+        assert_eq!(run_pipeline(promoting.clone()), 2);
+
+        let var_store = VarStore::new(Device::Cpu);
+        let _model = ConnectFourModel::new(&var_store.root());
+        let mut optimizer = Optimizer::new(
+            &var_store,
+            promoting.training.algorithm,
+            promoting.training.learning_rate,
+            promoting.training.weight_decay,
+        );
+
+        assert_eq!(load_bundle(&bundle_path(&directory, 2), &var_store, &mut optimizer).unwrap().2, 2);
+
+        // No candidate is promoted, the resumed run from step 3 keeps following the network promoted at step 2.
+        let resumed = PipelineConfig {
+            max_steps: Some(2),
+            resume: true,
+            ..config(&directory, 2f64)
+        };
+
+        assert_eq!(run_pipeline(resumed), 2);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::Rng;
+
+use crate::connect_four::{ConnectFourGame, ConnectFourModel, ConnectFourState, TranspositionTable, search_connect_four};
+use crate::replay::ReplayBuffer;
+use crate::search::SearchConfig;
+
+// Reanalyse (Schrittwieser et al. 2021): positions from the replay buffer are searched again with the latest network and
+// their targets replaced, so old games keep producing targets of the current strength.
+#[derive(Clone)]
+pub struct ReanalyseConfig {
+    // Positions reanalysed per training step as a fraction of the batch size, 0 disables reanalyse.
+    pub fraction: f64,
+    pub search: SearchConfig,
+    // Weight of the fresh search value in the new value target, the rest stays with the value target of the self-play
+    // game so its result is not lost however often the sample is reanalysed.
+    pub value_weight: f64,
+}
+
+impl Default for ReanalyseConfig {
+    fn default() -> Self {
+        ReanalyseConfig {
+            fraction: 0.0,
+            search: SearchConfig {
+                simulations: 100,
+                ..Default::default()
+            },
+            value_weight: 0.5,
+        }
+    }
+}
+
+// Searches `count` random positions of the buffer with `model` and overwrites their policy and value targets. Runs on
+// the trainer thread between optimizer steps: it rewrites samples of the trainer's own buffer with the weights being
+// trained, which a worker would need a shared buffer and a copy of the weights for, and `fraction` bounds its cost.
+pub fn reanalyse(model: &ConnectFourModel, buffer: &mut ReplayBuffer, config: &ReanalyseConfig, count: usize, rng: &mut impl Rng) {
+    if buffer.len() == 0 {
+        return;
+    }
+
+    let indices: Vec<usize> = (0..count).map(|_| rng.random_range(0..buffer.len())).collect();
+
+    reanalyse_samples(model, buffer, config, &indices);
+}
+
+fn reanalyse_samples(model: &ConnectFourModel, buffer: &mut ReplayBuffer, config: &ReanalyseConfig, indices: &[usize]) {
+    for &index in indices {
+        let sample = buffer.get_mut(index);
+
+        let mut game = ConnectFourGame::from_moves(&sample.history);
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+
+        let search_result = search_connect_four(state, &mut game, model, &config.search, &mut TranspositionTable::new());
+
+        let mut policy_target = [0f32; 7];
+
+        for statistics in &search_result.moves {
+            policy_target[statistics.game_move as usize] = statistics.target as f32;
+        }
+
+        sample.policy_target = Some(policy_target);
+        sample.value_target =
+            ((1f64 - config.value_weight) * sample.game_value_target as f64 + config.value_weight * search_result.value) as f32;
+    }
+
+    // The priorities were the losses against the old targets.
+    buffer.reset_priorities(indices);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use tch::{Device, nn};
+
+    use crate::connect_four::{ConnectFourGame, ConnectFourModel, ConnectFourState, TranspositionTable, search_connect_four};
+    use crate::reanalyse::{ReanalyseConfig, reanalyse_samples};
+    use crate::replay::{ReplayBuffer, Sampling};
+    use crate::search::SearchConfig;
+    use crate::self_play::Sample;
+
+    fn sample(history: Vec<i64>, value_target: f32) -> Sample {
+        Sample {
+            history,
+            policy_target: None,
+            value_target,
+            game_value_target: value_target,
+            moves_left: 1f32,
+            opponent_policy: None,
+            score_multiplier: None,
+        }
+    }
+
+    #[test]
+    fn test_reanalyse() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = ReanalyseConfig {
+            fraction: 1f64,
+            search: SearchConfig {
+                simulations: 20,
+                ..Default::default()
+            },
+            value_weight: 0.25,
+        };
+
+        // Column 0 of the reanalysed position is full.
+        let history = vec![0, 0, 0, 0, 0, 0];
+
+        let mut buffer = ReplayBuffer::new(3, Sampling::Prioritised { alpha: 1f64, beta: 1f64 });
+        buffer.extend([sample(vec![3], 0.5), sample(history.clone(), 1f32), sample(vec![], -1f32)]);
+        buffer.update_priorities(&[0, 1, 2], &[1f64, 0f64, 1f64]);
+
+        // Reanalysing twice blends the latest search value with the game's value target, not with the first blend.
+        reanalyse_samples(&model, &mut buffer, &config, &[1]);
+        reanalyse_samples(&model, &mut buffer, &config, &[1]);
+
+        let reanalysed = buffer.get(1);
+        let policy_target = reanalysed.policy_target.unwrap();
+
+        assert_eq!(policy_target[0], 0f32);
+        assert!((policy_target.iter().sum::<f32>() - 1f32).abs() < 1e-5);
+
+        let mut game = ConnectFourGame::from_moves(&history);
+        let state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+        let search_result = search_connect_four(state, &mut game, &model, &config.search, &mut TranspositionTable::new());
+
+        assert!((reanalysed.value_target as f64 - (0.75 + 0.25 * search_result.value)).abs() < 1e-5);
+        assert_eq!(reanalysed.game_value_target, 1f32);
+
+        for (index, value_target) in [(0, 0.5), (2, -1f32)] {
+            assert!(buffer.get(index).policy_target.is_none());
+            assert_eq!(buffer.get(index).value_target, value_target);
+        }
+
+        // The reanalysed sample is back at the highest priority instead of the near zero one of its old targets.
+        let (indices, _) = buffer.sample(256, &mut rand::rng());
+
+        assert!(indices.contains(&1));
+    }
+}
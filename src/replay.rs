@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+
+use rand::Rng;
+use rand::distr::Distribution;
+use rand::distr::weighted::WeightedIndex;
+
+use crate::self_play::Sample;
+
+#[derive(Clone, Copy)]
+pub enum Sampling {
+    Uniform,
+    // Prioritised experience replay (Schaul et al. 2016), samples are drawn proportionally to priority^alpha and
+    // weighted by (N * P(i))^-beta to correct for the bias.
+    Prioritised { alpha: f64, beta: f64 },
+}
+
+pub struct ReplayBuffer {
+    capacity: usize,
+    sampling: Sampling,
+    samples: VecDeque<Sample>,
+    priorities: VecDeque<f64>,
+    // New samples start at the highest priority seen so they are trained on at least once.
+    max_priority: f64,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize, sampling: Sampling) -> ReplayBuffer {
+        return ReplayBuffer {
+            capacity,
+            sampling,
+            samples: VecDeque::with_capacity(capacity),
+            priorities: VecDeque::with_capacity(capacity),
+            max_priority: 1f64,
+        };
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+            self.priorities.pop_front();
+        }
+
+        self.samples.push_back(sample);
+        self.priorities.push_back(self.max_priority);
+    }
+
+    pub fn extend(&mut self, samples: impl IntoIterator<Item = Sample>) {
+        for sample in samples {
+            self.push(sample);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.samples.len();
+    }
+
+    pub fn get(&self, index: usize) -> &Sample {
+        return &self.samples[index];
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut Sample {
+        return &mut self.samples[index];
+    }
+
+    // Returns the indices of a minibatch drawn with replacement and the importance sampling weight of each.
+    pub fn sample(&self, batch_size: usize, rng: &mut impl Rng) -> (Vec<usize>, Vec<f64>) {
+        match self.sampling {
+            Sampling::Uniform => {
+                let indices = (0..batch_size).map(|_| rng.random_range(0..self.samples.len())).collect();
+
+                (indices, vec![1f64; batch_size])
+            }
+            Sampling::Prioritised { alpha, beta } => {
+                let weights: Vec<f64> = self.priorities.iter().map(|priority| priority.powf(alpha)).collect();
+                let total: f64 = weights.iter().sum();
+
+                let distribution = WeightedIndex::new(&weights).unwrap();
+                let indices: Vec<usize> = (0..batch_size).map(|_| distribution.sample(rng)).collect();
+
+                let importance: Vec<f64> = indices
+                    .iter()
+                    .map(|&index| (self.samples.len() as f64 * weights[index] / total).powf(-beta))
+                    .collect();
+                let max_importance = importance.iter().cloned().fold(f64::MIN_POSITIVE, f64::max);
+
+                (indices, importance.iter().map(|weight| weight / max_importance).collect())
+            }
+        }
+    }
+
+    // Gives `indices` the highest priority seen, like new samples, once their targets changed and their loss is unknown.
+    pub fn reset_priorities(&mut self, indices: &[usize]) {
+        for &index in indices {
+            self.priorities[index] = self.max_priority;
+        }
+    }
+
+    pub fn update_priorities(&mut self, indices: &[usize], priorities: &[f64]) {
+        for (&index, &priority) in indices.iter().zip(priorities) {
+            self.priorities[index] = priority.max(1e-6);
+            self.max_priority = self.max_priority.max(priority);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::{ReplayBuffer, Sampling};
+    use crate::self_play::Sample;
+
+    fn sample(value_target: f32) -> Sample {
+        Sample {
+            history: Vec::new(),
+            policy_target: None,
+            value_target,
+            game_value_target: value_target,
+            moves_left: 1f32,
+            opponent_policy: None,
+            score_multiplier: None,
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut buffer = ReplayBuffer::new(2, Sampling::Uniform);
+        buffer.extend([sample(1f32), sample(2f32), sample(3f32)]);
+
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.get(0).value_target, 2f32);
+        assert_eq!(buffer.get(1).value_target, 3f32);
+    }
+
+    #[test]
+    fn test_prioritised() {
+        let mut buffer = ReplayBuffer::new(2, Sampling::Prioritised { alpha: 1f64, beta: 1f64 });
+        buffer.extend([sample(1f32), sample(2f32)]);
+        buffer.update_priorities(&[0, 1], &[0f64, 1f64]);
+
+        let (indices, weights) = buffer.sample(16, &mut rand::rng());
+
+        assert!(indices.iter().all(|&index| index == 1));
+        assert!(weights.iter().all(|&weight| weight == 1f64));
+    }
+}
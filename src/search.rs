@@ -0,0 +1,196 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub enum RootSelection {
+    Puct,
+    // Gumbel-top-k sampling of `considered_moves` candidates followed by sequential halving.
+    SequentialHalving {
+        considered_moves: usize,
+        c_visit: f64,
+        c_scale: f64,
+    },
+}
+
+#[derive(Clone)]
+pub struct SearchConfig {
+    pub simulations: usize,
+    // When set, search runs until the budget is spent instead of for a fixed number of simulations. Sequential halving
+    // keeps `simulations` as its plan and stops after the phase in which the budget runs out.
+    pub time_budget: Option<Duration>,
+
+    pub c_puct: f64,
+    // Value assumed for children that have not been visited yet.
+    pub fpu: f64,
+
+    pub dirichlet_alpha: f64,
+    // Fraction of the root priors replaced by Dirichlet noise, 0 disables noise.
+    pub dirichlet_fraction: f64,
+
+    // Temperature used when picking the move to play, 0 always plays the most visited move.
+    pub temperature: f64,
+
+    // Keep the subtree under the played move as the root of the next search.
+    pub reuse_tree: bool,
+    // Share search below positions reached through different move orders.
+    pub transpositions: bool,
+
+    pub root_selection: RootSelection,
+}
+
+impl RootSelection {
+    // Defaults of the Gumbel AlphaZero paper, 16 considered moves with c_visit 50 and c_scale 0.1.
+    pub fn sequential_halving() -> RootSelection {
+        return RootSelection::SequentialHalving {
+            considered_moves: 16,
+            c_visit: 50f64,
+            c_scale: 0.1,
+        };
+    }
+}
+
+impl SearchConfig {
+    pub fn finished(&self, start: Instant, simulations: usize) -> bool {
+        match self.time_budget {
+            Some(budget) => start.elapsed() >= budget,
+            None => simulations >= self.simulations,
+        }
+    }
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        SearchConfig {
+            simulations: 300,
+            time_budget: None,
+            c_puct: 0.5,
+            fpu: 0.0,
+            dirichlet_alpha: 1.0,
+            dirichlet_fraction: 0.0,
+            temperature: 0.0,
+            reuse_tree: true,
+            transpositions: false,
+            root_selection: RootSelection::Puct,
+        }
+    }
+}
+
+pub struct MoveStatistics {
+    pub game_move: i64,
+    pub visits: i64,
+    // Mean value from the perspective of the player choosing the move, None when the move was never visited.
+    pub value: Option<f64>,
+    pub prior: f64,
+    // Training target for the policy head, the visit distribution or the completed Q policy of sequential halving.
+    pub target: f64,
+    pub proven: Option<i64>,
+}
+
+pub struct SearchResult {
+    pub visits: i64,
+    pub value: f64,
+    pub moves: Vec<MoveStatistics>,
+    pub principal_variation: Vec<i64>,
+}
+
+impl SearchResult {
+    pub fn to_json(&self) -> String {
+        let moves: Vec<String> = self
+            .moves
+            .iter()
+            .map(|statistics| {
+                format!(
+                    "{{\"move\":{},\"visits\":{},\"value\":{},\"prior\":{},\"target\":{},\"proven\":{}}}",
+                    statistics.game_move,
+                    statistics.visits,
+                    statistics.value.map_or("null".to_string(), |value| value.to_string()),
+                    statistics.prior,
+                    statistics.target,
+                    statistics.proven.map_or("null".to_string(), |proven| proven.to_string()),
+                )
+            })
+            .collect();
+
+        let principal_variation: Vec<String> = self.principal_variation.iter().map(|game_move| game_move.to_string()).collect();
+
+        return format!(
+            "{{\"visits\":{},\"value\":{},\"principal_variation\":[{}],\"moves\":[{}]}}",
+            self.visits,
+            self.value,
+            principal_variation.join(","),
+            moves.join(","),
+        );
+    }
+}
+
+impl fmt::Display for SearchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>4} {:>7} {:>6} {:>6} {:>6} {:>6}",
+            "Move", "Visits", "Value", "Prior", "Target", "Proven"
+        )?;
+
+        for statistics in &self.moves {
+            writeln!(
+                f,
+                "{:>4} {:>7} {:>6} {:>6.2} {:>6.2} {:>6}",
+                statistics.game_move,
+                statistics.visits,
+                statistics.value.map_or("-".to_string(), |value| format!("{:.2}", value)),
+                statistics.prior,
+                statistics.target,
+                statistics.proven.map_or("-".to_string(), |proven| proven.to_string()),
+            )?;
+        }
+
+        let principal_variation: Vec<String> = self.principal_variation.iter().map(|game_move| game_move.to_string()).collect();
+
+        write!(
+            f,
+            "Value {:.2} over {} visits, principal variation {}",
+            self.value,
+            self.visits,
+            principal_variation.join(" ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{MoveStatistics, SearchResult};
+
+    #[test]
+    fn test_to_json() {
+        let result = SearchResult {
+            visits: 3,
+            value: 0.5,
+            moves: vec![
+                MoveStatistics {
+                    game_move: 0,
+                    visits: 3,
+                    value: Some(0.5),
+                    prior: 0.25,
+                    target: 1f64,
+                    proven: None,
+                },
+                MoveStatistics {
+                    game_move: 1,
+                    visits: 0,
+                    value: None,
+                    prior: 0.75,
+                    target: 0f64,
+                    proven: Some(-1),
+                },
+            ],
+            principal_variation: Vec::new(),
+        };
+
+        assert_eq!(
+            result.to_json(),
+            "{\"visits\":3,\"value\":0.5,\"principal_variation\":[],\"moves\":[\
+             {\"move\":0,\"visits\":3,\"value\":0.5,\"prior\":0.25,\"target\":1,\"proven\":null},\
+             {\"move\":1,\"visits\":0,\"value\":null,\"prior\":0.75,\"target\":0,\"proven\":-1}]}"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use rand::Rng;
+
+use crate::connect_four::{
+    ConnectFourGame, ConnectFourModel, ConnectFourState, TranspositionTable, advance_root, search_connect_four, select_move,
+};
+use crate::game::Game;
+use crate::search::{RootSelection, SearchConfig};
+use crate::targets::{ValueTargetConfig, value_targets};
+
+// Playout cap randomisation (Wu 2019): each move is searched with `full_search` with probability `full_search_probability`
+// and with the cheaper `fast_search` otherwise. Only full searches produce policy targets. A probability of 1 disables it.
+#[derive(Clone)]
+pub struct SelfPlayConfig {
+    pub full_search: SearchConfig,
+    pub fast_search: SearchConfig,
+    pub full_search_probability: f64,
+    // Moves from the start of the game picked with the temperature of their search, later moves are played greedily.
+    pub temperature_moves: usize,
+
+    pub value_targets: ValueTargetConfig,
+}
+
+impl Default for SelfPlayConfig {
+    fn default() -> Self {
+        // Root noise and an opening temperature keep workers playing the same weights from repeating the same game.
+        SelfPlayConfig {
+            full_search: SearchConfig {
+                dirichlet_fraction: 0.25,
+                temperature: 1.0,
+                ..Default::default()
+            },
+            fast_search: SearchConfig {
+                simulations: 50,
+                temperature: 1.0,
+                ..Default::default()
+            },
+            full_search_probability: 1.0,
+            temperature_moves: 10,
+            value_targets: ValueTargetConfig::default(),
+        }
+    }
+}
+
+impl SelfPlayConfig {
+    // Uses `root_selection` for both the full and the fast searches.
+    pub fn with_root_selection(self, root_selection: RootSelection) -> SelfPlayConfig {
+        return SelfPlayConfig {
+            full_search: SearchConfig {
+                root_selection: root_selection.clone(),
+                ..self.full_search
+            },
+            fast_search: SearchConfig {
+                root_selection,
+                ..self.fast_search
+            },
+            ..self
+        };
+    }
+}
+
+pub struct Sample {
+    // Moves played from the empty board up to this position.
+    pub history: Vec<i64>,
+    pub policy_target: Option<[f32; 7]>,
+    pub value_target: f32,
+    // Value target of the self-play game, kept when reanalyse blends search values into `value_target`.
+    pub game_value_target: f32,
+
+    // Auxiliary targets: moves left in the game including this one, the policy target of the opponent's reply and the
+    // score multiplier the game ended with.
+    pub moves_left: f32,
+    pub opponent_policy: Option<[f32; 7]>,
+    pub score_multiplier: Option<f32>,
+}
+
+fn transform_policy<G: Game>(policy: &[f32; 7], symmetry: usize) -> [f32; 7] {
+    let mut transformed = [0f32; 7];
+
+    for (game_move, target) in policy.iter().enumerate() {
+        transformed[G::transform_action(symmetry, game_move as i64) as usize] = *target;
+    }
+
+    return transformed;
+}
+
+impl Sample {
+    pub fn transformed<G: Game>(&self, symmetry: usize) -> Sample {
+        return Sample {
+            history: self
+                .history
+                .iter()
+                .map(|&game_move| G::transform_action(symmetry, game_move))
+                .collect(),
+            policy_target: self
+                .policy_target
+                .map(|policy_target| transform_policy::<G>(&policy_target, symmetry)),
+            value_target: self.value_target,
+            game_value_target: self.game_value_target,
+            moves_left: self.moves_left,
+            opponent_policy: self
+                .opponent_policy
+                .map(|opponent_policy| transform_policy::<G>(&opponent_policy, symmetry)),
+            score_multiplier: self.score_multiplier,
+        };
+    }
+}
+
+pub struct SelfPlayGame {
+    pub samples: Vec<Sample>,
+    pub result: i64,
+    // Simulations run searching the moves of the game, fewer than configured when a time budget or a proven root ends a
+    // search early. Visits of reused subtrees are not counted again.
+    pub simulations: usize,
+}
+
+pub fn self_play_game(model: &ConnectFourModel, config: &SelfPlayConfig, display: bool) -> SelfPlayGame {
+    let mut game = ConnectFourGame::new();
+    let mut state = Rc::new(RefCell::new(ConnectFourState::new(0f64)));
+    let mut table = TranspositionTable::new();
+
+    let mut rng = rand::rng();
+    let mut samples = Vec::new();
+    let mut players = Vec::new();
+    let mut search_values = Vec::new();
+    let mut simulations = 0;
+
+    while !game.is_terminal() {
+        if display {
+            game.display();
+        }
+
+        let full_search = rng.random_bool(config.full_search_probability.clamp(0f64, 1f64));
+        let search_config = if full_search { &config.full_search } else { &config.fast_search };
+
+        let reused_visits = state.borrow().visits;
+        let search_result = search_connect_four(state.clone(), &mut game, model, search_config, &mut table);
+
+        simulations += (search_result.visits - reused_visits) as usize;
+
+        if display {
+            println!("{}", search_result);
+        }
+
+        let policy_target = if full_search {
+            let mut policy_target = [0f32; 7];
+
+            for statistics in &search_result.moves {
+                policy_target[statistics.game_move as usize] = statistics.target as f32;
+            }
+
+            Some(policy_target)
+        } else {
+            None
+        };
+
+        samples.push(Sample {
+            history: game.history.clone(),
+            policy_target,
+            value_target: 0f32,
+            game_value_target: 0f32,
+            moves_left: 0f32,
+            opponent_policy: None,
+            score_multiplier: None,
+        });
+        players.push(game.current_player());
+        search_values.push(search_result.value);
+
+        let best_move = if game.history.len() < config.temperature_moves {
+            select_move(&state, search_config)
+        } else {
+            select_move(
+                &state,
+                &SearchConfig {
+                    temperature: 0f64,
+                    ..search_config.clone()
+                },
+            )
+        };
+
+        game.make_move(best_move);
+
+        state = advance_root(state, best_move, search_config, &mut table);
+    }
+
+    let result = game.result();
+
+    let targets = value_targets(&game, &players, &search_values, &config.value_targets);
+
+    for (sample, target) in samples.iter_mut().zip(targets) {
+        sample.value_target = target as f32;
+        sample.game_value_target = target as f32;
+    }
+
+    let length = samples.len();
+
+    for i in 0..length {
+        samples[i].moves_left = (length - i) as f32;
+        samples[i].opponent_policy = samples.get(i + 1).and_then(|next| next.policy_target);
+        samples[i].score_multiplier = game.score_multiplier().map(|multiplier| multiplier as f32);
+    }
+
+    if display {
+        game.display();
+
+        println!("Finished game with result {}", result);
+    }
+
+    return SelfPlayGame {
+        samples,
+        result,
+        simulations,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use tch::{Device, nn};
+
+    use crate::connect_four::{ConnectFourGame, ConnectFourModel};
+    use crate::search::SearchConfig;
+    use crate::self_play::{Sample, SelfPlayConfig, self_play_game};
+
+    fn config(full_search_probability: f64) -> SelfPlayConfig {
+        return SelfPlayConfig {
+            full_search: SearchConfig {
+                simulations: 10,
+                ..Default::default()
+            },
+            fast_search: SearchConfig {
+                simulations: 5,
+                ..Default::default()
+            },
+            full_search_probability,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn test_distinct_games() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let mut config = SelfPlayConfig::default();
+
+        config.full_search.simulations = 10;
+
+        let games: HashSet<Vec<i64>> = (0..4)
+            .map(|_| self_play_game(&model, &config, false).samples.last().unwrap().history.clone())
+            .collect();
+
+        assert!(games.len() > 1);
+    }
+
+    #[test]
+    fn test_fast_searches() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = config(0f64);
+        let game = self_play_game(&model, &config, false);
+
+        assert!(game.samples.iter().all(|sample| sample.policy_target.is_none()));
+        assert!(game.simulations > 0 && game.simulations <= game.samples.len() * 5);
+    }
+
+    #[test]
+    fn test_full_searches() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let config = config(1f64);
+        let game = self_play_game(&model, &config, false);
+
+        assert!(game.samples.iter().all(|sample| sample.policy_target.is_some()));
+        assert!(game.simulations > 0 && game.simulations <= game.samples.len() * 10);
+    }
+
+    #[test]
+    fn test_time_budget_simulations() {
+        let var_store = nn::VarStore::new(Device::Cpu);
+        let model = ConnectFourModel::new(&var_store.root());
+
+        let mut config = config(1f64);
+
+        config.full_search.time_budget = Some(Duration::ZERO);
+
+        let game = self_play_game(&model, &config, false);
+
+        // Every search only expands its root, the configured simulations are never run.
+        assert!(game.simulations <= game.samples.len());
+    }
+
+    #[test]
+    fn test_mirrored_sample() {
+        let sample = Sample {
+            history: vec![0, 1, 1, 2, 6],
+            policy_target: Some([0.5f32, 0.25f32, 0f32, 0f32, 0f32, 0f32, 0.25f32]),
+            value_target: 0.5f32,
+            game_value_target: 0.5f32,
+            moves_left: 3f32,
+            opponent_policy: Some([0f32, 0f32, 1f32, 0f32, 0f32, 0f32, 0f32]),
+            score_multiplier: None,
+        };
+
+        let mirrored = sample.transformed::<ConnectFourGame>(1);
+
+        assert_eq!(mirrored.policy_target, Some([0.25f32, 0f32, 0f32, 0f32, 0f32, 0.25f32, 0.5f32]));
+        assert_eq!(mirrored.opponent_policy, Some([0f32, 0f32, 0f32, 0f32, 1f32, 0f32, 0f32]));
+        assert_eq!(mirrored.value_target, sample.value_target);
+        assert_eq!(mirrored.moves_left, sample.moves_left);
+
+        let game = ConnectFourGame::from_moves(&sample.history);
+        let mirrored_game = ConnectFourGame::from_moves(&mirrored.history);
+
+        for y in 0..6 {
+            for x in 0..7 {
+                assert_eq!(
+                    game.board_state[game.position_to_index(x, y)],
+                    mirrored_game.board_state[mirrored_game.position_to_index(6 - x, y)]
+                );
+            }
+        }
+    }
+}
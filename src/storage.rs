@@ -0,0 +1,548 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::search::{RootSelection, SearchConfig};
+use crate::self_play::{Sample, SelfPlayConfig, SelfPlayGame};
+
+// Self-play games are stored in a directory of shards `shard_<index>.bin`. Every shard starts with `MAGIC` and a format
+// version, followed by one frame per game: the length of the zlib compressed record as a little endian u32 and the record.
+// Frames are only ever appended, a frame cut short by a crash is ignored when reading.
+//
+// Record layout, all little endian:
+//   u64 model version, full search, fast search, f32 full search probability, i8 result, u32 simulations,
+//   u16 samples
+//   per search: u32 simulations, f32 c_puct, f32 dirichlet fraction, u8 root selection
+//   per sample: u8 history length, u8 moves, policy, f32 game value, u8 moves left, opponent policy, u8 has multiplier,
+//   f32 multiplier if present
+// Policies are stored sparsely as u8 entries followed by (u8 move, f32 probability) per entry, a missing policy has no
+// entries.
+const MAGIC: &[u8; 4] = b"DDZS";
+const VERSION: u16 = 1;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SearchMetadata {
+    pub simulations: u32,
+    pub c_puct: f32,
+    pub dirichlet_fraction: f32,
+    pub sequential_halving: bool,
+}
+
+impl SearchMetadata {
+    fn new(config: &SearchConfig) -> SearchMetadata {
+        return SearchMetadata {
+            simulations: config.simulations as u32,
+            c_puct: config.c_puct as f32,
+            dirichlet_fraction: config.dirichlet_fraction as f32,
+            sequential_halving: matches!(config.root_selection, RootSelection::SequentialHalving { .. }),
+        };
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct GameMetadata {
+    pub model_version: u64,
+    // Searches of the playout cap randomisation the game was played with and the probability of a full search per move.
+    pub full_search: SearchMetadata,
+    pub fast_search: SearchMetadata,
+    pub full_search_probability: f32,
+}
+
+impl GameMetadata {
+    pub fn new(model_version: u64, config: &SelfPlayConfig) -> GameMetadata {
+        return GameMetadata {
+            model_version,
+            full_search: SearchMetadata::new(&config.full_search),
+            fast_search: SearchMetadata::new(&config.fast_search),
+            full_search_probability: config.full_search_probability as f32,
+        };
+    }
+}
+
+pub struct GameRecord {
+    pub metadata: GameMetadata,
+    pub game: SelfPlayGame,
+}
+
+fn encode(metadata: &GameMetadata, game: &SelfPlayGame) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    bytes.extend_from_slice(&metadata.model_version.to_le_bytes());
+    encode_search(&mut bytes, &metadata.full_search);
+    encode_search(&mut bytes, &metadata.fast_search);
+    bytes.extend_from_slice(&metadata.full_search_probability.to_le_bytes());
+    bytes.push(game.result as i8 as u8);
+    bytes.extend_from_slice(&(game.simulations as u32).to_le_bytes());
+    bytes.extend_from_slice(&(game.samples.len() as u16).to_le_bytes());
+
+    for sample in &game.samples {
+        bytes.push(sample.history.len() as u8);
+        bytes.extend(sample.history.iter().map(|&game_move| game_move as u8));
+
+        encode_policy(&mut bytes, &sample.policy_target);
+        bytes.extend_from_slice(&sample.game_value_target.to_le_bytes());
+
+        bytes.push(sample.moves_left as u8);
+        encode_policy(&mut bytes, &sample.opponent_policy);
+
+        match sample.score_multiplier {
+            Some(multiplier) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&multiplier.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+    }
+
+    return bytes;
+}
+
+fn encode_search(bytes: &mut Vec<u8>, search: &SearchMetadata) {
+    bytes.extend_from_slice(&search.simulations.to_le_bytes());
+    bytes.extend_from_slice(&search.c_puct.to_le_bytes());
+    bytes.extend_from_slice(&search.dirichlet_fraction.to_le_bytes());
+    bytes.push(search.sequential_halving as u8);
+}
+
+fn encode_policy(bytes: &mut Vec<u8>, policy: &Option<[f32; 7]>) {
+    let entries: Vec<(usize, f32)> = policy
+        .iter()
+        .flat_map(|policy| policy.iter().cloned().enumerate())
+        .filter(|&(_, probability)| probability > 0f32)
+        .collect();
+
+    bytes.push(entries.len() as u8);
+
+    for (game_move, probability) in entries {
+        bytes.push(game_move as u8);
+        bytes.extend_from_slice(&probability.to_le_bytes());
+    }
+}
+
+fn decode_policy(reader: &mut impl Read) -> io::Result<Option<[f32; 7]>> {
+    let entries = read_bytes::<1>(reader)?[0];
+
+    if entries == 0 {
+        return Ok(None);
+    }
+
+    let mut policy = [0f32; 7];
+
+    for _ in 0..entries {
+        let game_move = read_move(reader)? as usize;
+
+        policy[game_move] = f32::from_le_bytes(read_bytes(reader)?);
+    }
+
+    return Ok(Some(policy));
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+
+    reader.read_exact(&mut bytes)?;
+
+    return Ok(bytes);
+}
+
+// Reads a move, which must be a column of the board. Frames that decompress but hold anything else are invalid data.
+fn read_move(reader: &mut impl Read) -> io::Result<u8> {
+    let game_move = read_bytes::<1>(reader)?[0];
+
+    if game_move >= 7 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid move {} in game record", game_move),
+        ));
+    }
+
+    return Ok(game_move);
+}
+
+fn decode_search(reader: &mut impl Read) -> io::Result<SearchMetadata> {
+    return Ok(SearchMetadata {
+        simulations: u32::from_le_bytes(read_bytes(reader)?),
+        c_puct: f32::from_le_bytes(read_bytes(reader)?),
+        dirichlet_fraction: f32::from_le_bytes(read_bytes(reader)?),
+        sequential_halving: read_bytes::<1>(reader)?[0] != 0,
+    });
+}
+
+fn decode(reader: &mut impl Read) -> io::Result<GameRecord> {
+    let metadata = GameMetadata {
+        model_version: u64::from_le_bytes(read_bytes(reader)?),
+        full_search: decode_search(reader)?,
+        fast_search: decode_search(reader)?,
+        full_search_probability: f32::from_le_bytes(read_bytes(reader)?),
+    };
+
+    let result = read_bytes::<1>(reader)?[0] as i8 as i64;
+    let simulations = u32::from_le_bytes(read_bytes(reader)?) as usize;
+    let sample_count = u16::from_le_bytes(read_bytes(reader)?);
+
+    let mut samples = Vec::with_capacity(sample_count as usize);
+
+    for _ in 0..sample_count {
+        let history_length = read_bytes::<1>(reader)?[0] as usize;
+        let history = (0..history_length).map(|_| read_move(reader)).collect::<io::Result<Vec<u8>>>()?;
+
+        let policy_target = decode_policy(reader)?;
+        let value_target = f32::from_le_bytes(read_bytes(reader)?);
+
+        let moves_left = read_bytes::<1>(reader)?[0] as f32;
+        let opponent_policy = decode_policy(reader)?;
+
+        let score_multiplier = match read_bytes::<1>(reader)?[0] {
+            0 => None,
+            _ => Some(f32::from_le_bytes(read_bytes(reader)?)),
+        };
+
+        samples.push(Sample {
+            history: history.into_iter().map(|game_move| game_move as i64).collect(),
+            policy_target,
+            value_target,
+            game_value_target: value_target,
+            moves_left,
+            opponent_policy,
+            score_multiplier,
+        });
+    }
+
+    return Ok(GameRecord {
+        metadata,
+        game: SelfPlayGame {
+            samples,
+            result,
+            simulations,
+        },
+    });
+}
+
+fn shard_path(directory: &Path, index: usize) -> PathBuf {
+    return directory.join(format!("shard_{:05}.bin", index));
+}
+
+fn shard_index(path: &Path) -> Option<usize> {
+    return path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("shard_"))
+        .and_then(|name| name.strip_suffix(".bin"))
+        .and_then(|index| index.parse::<usize>().ok());
+}
+
+// Shards in `directory` sorted from oldest to newest.
+pub fn list_shards(directory: &Path) -> Vec<PathBuf> {
+    let mut shards: Vec<(usize, PathBuf)> = fs::read_dir(directory)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter_map(|path| Some((shard_index(&path)?, path)))
+        .collect();
+
+    shards.sort();
+
+    return shards.into_iter().map(|(_, path)| path).collect();
+}
+
+pub struct GameWriter {
+    directory: PathBuf,
+    games_per_shard: usize,
+    shard_index: usize,
+    shard_games: usize,
+    file: Option<File>,
+}
+
+impl GameWriter {
+    // Appends to a new shard after the newest one, so restarts never rewrite earlier data even when older shards were
+    // deleted.
+    pub fn new(directory: &Path, games_per_shard: usize) -> io::Result<GameWriter> {
+        fs::create_dir_all(directory)?;
+
+        let next_index = list_shards(directory)
+            .last()
+            .and_then(|path| shard_index(path))
+            .map_or(0, |index| index + 1);
+
+        return Ok(GameWriter {
+            directory: directory.to_path_buf(),
+            games_per_shard,
+            shard_index: next_index,
+            shard_games: 0,
+            file: None,
+        });
+    }
+
+    pub fn write(&mut self, metadata: &GameMetadata, game: &SelfPlayGame) -> io::Result<()> {
+        if self.file.is_none() || self.shard_games >= self.games_per_shard {
+            let mut file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(shard_path(&self.directory, self.shard_index))?;
+
+            file.write_all(MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+
+            self.file = Some(file);
+            self.shard_index += 1;
+            self.shard_games = 0;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&encode(metadata, game))?;
+        let compressed = encoder.finish()?;
+
+        let file = self.file.as_mut().unwrap();
+
+        let mut frame = Vec::with_capacity(4 + compressed.len());
+        frame.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&compressed);
+
+        file.write_all(&frame)?;
+        file.flush()?;
+
+        self.shard_games += 1;
+
+        return Ok(());
+    }
+}
+
+pub fn read_shard(path: &Path) -> io::Result<Vec<GameRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let magic: [u8; 4] = read_bytes(&mut reader)?;
+    let version = u16::from_le_bytes(read_bytes(&mut reader)?);
+
+    if &magic != MAGIC || version != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a game shard of version {}", path.display(), VERSION),
+        ));
+    }
+
+    let mut records = Vec::new();
+
+    loop {
+        let length = match read_bytes::<4>(&mut reader) {
+            Ok(length) => u32::from_le_bytes(length) as usize,
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+
+        let mut compressed = vec![0u8; length];
+
+        match reader.read_exact(&mut compressed) {
+            Ok(()) => {}
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+
+        records.push(decode(&mut ZlibDecoder::new(compressed.as_slice()))?);
+    }
+
+    return Ok(records);
+}
+
+// Samples of the most recent games in `directory`, oldest first, stopping once `capacity` samples are collected. Shards
+// that cannot be read are skipped rather than keeping the trainer from starting, and returned with their error.
+pub fn load_recent_samples(directory: &Path, capacity: usize) -> io::Result<(Vec<Sample>, Vec<(PathBuf, io::Error)>)> {
+    let mut games: Vec<SelfPlayGame> = Vec::new();
+    let mut skipped = Vec::new();
+    let mut sample_count = 0;
+
+    for shard in list_shards(directory).iter().rev() {
+        let records = match read_shard(shard) {
+            Ok(records) => records,
+            Err(error) => {
+                skipped.push((shard.clone(), error));
+
+                continue;
+            }
+        };
+
+        for record in records.into_iter().rev() {
+            sample_count += record.game.samples.len();
+
+            games.push(record.game);
+        }
+
+        if sample_count >= capacity {
+            break;
+        }
+    }
+
+    let mut samples: Vec<Sample> = games.into_iter().rev().flat_map(|game| game.samples).collect();
+
+    if samples.len() > capacity {
+        samples.drain(..samples.len() - capacity);
+    }
+
+    return Ok((samples, skipped));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{ErrorKind, Write};
+    use std::path::PathBuf;
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    use crate::self_play::{Sample, SelfPlayConfig, SelfPlayGame};
+    use crate::storage::{GameMetadata, GameWriter, VERSION, encode, list_shards, load_recent_samples, read_shard};
+
+    fn game(value_target: f32) -> SelfPlayGame {
+        SelfPlayGame {
+            samples: vec![
+                Sample {
+                    history: vec![],
+                    policy_target: Some([0f32, 0.25f32, 0f32, 0.75f32, 0f32, 0f32, 0f32]),
+                    value_target,
+                    game_value_target: value_target,
+                    moves_left: 2f32,
+                    opponent_policy: None,
+                    score_multiplier: None,
+                },
+                Sample {
+                    history: vec![3],
+                    policy_target: None,
+                    value_target: -value_target,
+                    game_value_target: -value_target,
+                    moves_left: 1f32,
+                    opponent_policy: Some([1f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32]),
+                    score_multiplier: Some(2f32),
+                },
+            ],
+            result: 1,
+            simulations: 15,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_storage_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        let config = SelfPlayConfig {
+            full_search_probability: 0.25,
+            ..Default::default()
+        };
+
+        let metadata = GameMetadata::new(1200, &config);
+
+        assert_eq!(metadata.fast_search.simulations, 50);
+        assert_eq!(metadata.full_search.dirichlet_fraction, 0.25);
+
+        let mut writer = GameWriter::new(&directory, 2).unwrap();
+
+        for i in 0..3 {
+            writer.write(&metadata, &game(i as f32)).unwrap();
+        }
+
+        let shards = list_shards(&directory);
+
+        assert_eq!(shards.len(), 2);
+
+        let records = read_shard(&shards[0]).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].metadata, metadata);
+        assert_eq!(records[1].game.result, 1);
+        assert_eq!(records[1].game.simulations, 15);
+        assert_eq!(records[1].game.samples[0].policy_target, game(1f32).samples[0].policy_target);
+        assert_eq!(records[1].game.samples[1].history, vec![3]);
+        assert_eq!(records[1].game.samples[1].policy_target, None);
+        assert_eq!(records[1].game.samples[0].moves_left, 2f32);
+        assert_eq!(records[1].game.samples[0].score_multiplier, None);
+        assert_eq!(records[1].game.samples[1].opponent_policy, game(1f32).samples[1].opponent_policy);
+        assert_eq!(records[1].game.samples[1].score_multiplier, Some(2f32));
+
+        let (samples, skipped) = load_recent_samples(&directory, 3).unwrap();
+        let values: Vec<f32> = samples.iter().map(|sample| sample.value_target).collect();
+
+        assert_eq!(values, vec![-1f32, 2f32, -2f32]);
+        assert!(skipped.is_empty());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_deleted_and_corrupt_shards() {
+        let directory = std::env::temp_dir().join(format!("alpha_dou_dizhu_shards_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        let metadata = GameMetadata::new(0, &SelfPlayConfig::default());
+
+        let mut writer = GameWriter::new(&directory, 1).unwrap();
+
+        for i in 0..3 {
+            writer.write(&metadata, &game(i as f32)).unwrap();
+        }
+
+        fs::remove_file(directory.join("shard_00000.bin")).unwrap();
+
+        let mut writer = GameWriter::new(&directory, 1).unwrap();
+
+        for i in 3..5 {
+            writer.write(&metadata, &game(i as f32)).unwrap();
+        }
+
+        assert!(list_shards(&directory).last().unwrap().ends_with("shard_00004.bin"));
+
+        let mut corrupt = b"DDZS".to_vec();
+        corrupt.extend_from_slice(&VERSION.to_le_bytes());
+        corrupt.extend_from_slice(&5u32.to_le_bytes());
+        corrupt.extend_from_slice(b"xxxxx");
+
+        fs::write(directory.join("shard_00001.bin"), corrupt).unwrap();
+
+        // Frames that decompress to records with a column outside the board, in the policy of the first sample and in the
+        // history of the second, which starts 19 bytes after the first.
+        let header_length = encode(
+            &metadata,
+            &SelfPlayGame {
+                samples: vec![],
+                result: 0,
+                simulations: 0,
+            },
+        )
+        .len();
+
+        for (index, offset) in [(2, header_length + 2), (3, header_length + 20)] {
+            let mut record = encode(&metadata, &game(index as f32));
+            record[offset] = 7;
+
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&record).unwrap();
+            let compressed = encoder.finish().unwrap();
+
+            let mut shard = b"DDZS".to_vec();
+            shard.extend_from_slice(&VERSION.to_le_bytes());
+            shard.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            shard.extend_from_slice(&compressed);
+
+            let path = directory.join(format!("shard_{:05}.bin", index));
+
+            fs::write(&path, shard).unwrap();
+
+            assert_eq!(read_shard(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+
+        let (samples, skipped) = load_recent_samples(&directory, 100).unwrap();
+        let values: Vec<f32> = samples.iter().map(|sample| sample.value_target).collect();
+
+        assert_eq!(values, vec![4f32, -4f32]);
+
+        let skipped: Vec<PathBuf> = skipped.into_iter().map(|(shard, _)| shard).collect();
+
+        assert_eq!(skipped, [3, 2, 1].map(|index| directory.join(format!("shard_{:05}.bin", index))));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use crate::game::Game;
+
+#[derive(Clone)]
+pub struct ValueTargetConfig {
+    // Per ply discount of the final return, 1 trains on the plain game result.
+    pub discount: f64,
+    // Bootstrap from the search value this many plies ahead instead of waiting for the final result.
+    pub bootstrap_steps: Option<usize>,
+}
+
+impl Default for ValueTargetConfig {
+    fn default() -> Self {
+        ValueTargetConfig {
+            discount: 1.0,
+            bootstrap_steps: None,
+        }
+    }
+}
+
+// `players[t]` is the player to move at the t-th position of the finished `game` and `search_values[t]` the root search
+// value from their perspective. Bootstrapped values are negated across players, which assumes a two player zero sum game.
+pub fn value_targets(game: &impl Game, players: &[usize], search_values: &[f64], config: &ValueTargetConfig) -> Vec<f64> {
+    return (0..players.len())
+        .map(|ply| {
+            let remaining = players.len() - ply;
+
+            match config.bootstrap_steps {
+                Some(steps) if steps < remaining => {
+                    let value = search_values[ply + steps];
+                    let value = if players[ply + steps] == players[ply] { value } else { -value };
+
+                    config.discount.powi(steps as i32) * value
+                }
+                _ => config.discount.powi(remaining as i32 - 1) * game.returns(players[ply]),
+            }
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connect_four::ConnectFourGame;
+    use crate::game::Game;
+    use crate::targets::{ValueTargetConfig, value_targets};
+
+    fn play(moves: &[i64]) -> (ConnectFourGame, Vec<usize>) {
+        let mut game = ConnectFourGame::new();
+        let mut players = Vec::new();
+
+        for &game_move in moves {
+            players.push(game.current_player());
+            game.make_move(game_move);
+        }
+
+        assert!(game.is_terminal());
+
+        (game, players)
+    }
+
+    #[test]
+    fn test_first_player_win() {
+        let (game, players) = play(&[0, 1, 0, 1, 0, 1, 0]);
+        let targets = value_targets(&game, &players, &[0f64; 7], &ValueTargetConfig::default());
+
+        assert_eq!(targets, vec![1f64, -1f64, 1f64, -1f64, 1f64, -1f64, 1f64]);
+    }
+
+    #[test]
+    fn test_second_player_win() {
+        let (game, players) = play(&[0, 1, 0, 1, 0, 1, 2, 1]);
+        let targets = value_targets(&game, &players, &[0f64; 8], &ValueTargetConfig::default());
+
+        assert_eq!(targets, vec![-1f64, 1f64, -1f64, 1f64, -1f64, 1f64, -1f64, 1f64]);
+    }
+
+    #[test]
+    fn test_draw() {
+        let (game, players) = play(&[
+            4, 3, 6, 0, 1, 4, 5, 5, 1, 1, 5, 0, 1, 6, 0, 1, 5, 5, 1, 0, 4, 6, 3, 2, 6, 6, 0, 4, 6, 5, 2, 0, 4, 2, 4, 2, 2, 2, 3, 3, 3, 3,
+        ]);
+        let targets = value_targets(&game, &players, &[0.5f64; 42], &ValueTargetConfig::default());
+
+        assert_eq!(game.result(), 0);
+        assert!(targets.iter().all(|&target| target == 0f64));
+    }
+
+    #[test]
+    fn test_discount() {
+        let (game, players) = play(&[0, 1, 0, 1, 0, 1, 0]);
+        let config = ValueTargetConfig {
+            discount: 0.5,
+            bootstrap_steps: None,
+        };
+        let targets = value_targets(&game, &players, &[0f64; 7], &config);
+
+        assert_eq!(targets[6], 1f64);
+        assert_eq!(targets[5], -0.5f64);
+        assert_eq!(targets[4], 0.25f64);
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let (game, players) = play(&[0, 1, 0, 1, 0, 1, 0]);
+        let config = ValueTargetConfig {
+            discount: 1.0,
+            bootstrap_steps: Some(3),
+        };
+        let search_values = [0.1f64, 0.2f64, 0.3f64, 0.4f64, 0.5f64, 0.6f64, 0.7f64];
+        let targets = value_targets(&game, &players, &search_values, &config);
+
+        assert_eq!(targets[0], -0.4f64);
+        assert_eq!(targets[1], -0.5f64);
+        assert_eq!(targets[3], -0.7f64);
+        assert_eq!(targets[4], 1f64);
+        assert_eq!(targets[5], -1f64);
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use tch::Device;
+use tch::nn::VarStore;
+
+use crate::arena::{MatchResult, play_match};
+use crate::checkpoint::{list_checkpoints, load_model};
+use crate::connect_four::ConnectFourModel;
+use crate::search::SearchConfig;
+
+pub struct Participant {
+    pub name: String,
+    pub vs: VarStore,
+    pub model: ConnectFourModel,
+    pub elo: f64,
+    // Standard error of `elo`.
+    pub uncertainty: f64,
+}
+
+impl Participant {
+    pub fn new(name: String, vs: VarStore, model: ConnectFourModel) -> Participant {
+        return Participant {
+            name,
+            vs,
+            model,
+            elo: 1500f64,
+            uncertainty: 0f64,
+        };
+    }
+}
+
+#[derive(Clone)]
+pub struct TournamentConfig {
+    // Games between every pair of participants, colours alternate between games.
+    pub games_per_pair: usize,
+    pub search: SearchConfig,
+    // Virtual draws added between every pair as in BayesElo, keeps ratings finite when a pair has a perfect score.
+    pub prior_draws: f64,
+    // Network evaluations each participant keeps across its games.
+    pub cache_capacity: usize,
+}
+
+impl Default for TournamentConfig {
+    fn default() -> Self {
+        TournamentConfig {
+            games_per_pair: 10,
+            search: SearchConfig {
+                simulations: 100,
+                ..Default::default()
+            },
+            prior_draws: 2f64,
+            cache_capacity: 100_000,
+        }
+    }
+}
+
+// Checkpoints in `directory` whose step is a multiple of `every`.
+pub fn load_participants(directory: &Path, every: usize, config: &TournamentConfig) -> Vec<Participant> {
+    return list_checkpoints(directory)
+        .into_iter()
+        .filter(|(step, _)| step % every == 0)
+        .map(|(step, path)| {
+            let (vs, model) = load_model(&path, Device::cuda_if_available()).unwrap();
+
+            Participant::new(format!("connect_four_{:05}", step), vs, model.with_cache(config.cache_capacity))
+        })
+        .collect();
+}
+
+// Plays every pair and returns the results, `results[i][j]` is the result of participant i against participant j.
+pub fn round_robin(participants: &[Participant], config: &TournamentConfig) -> Vec<Vec<MatchResult>> {
+    let mut results = vec![vec![MatchResult::default(); participants.len()]; participants.len()];
+
+    for i in 0..participants.len() {
+        for j in (i + 1)..participants.len() {
+            let result = play_match(
+                &participants[i].model,
+                &participants[j].model,
+                &config.search,
+                config.games_per_pair,
+            );
+
+            println!(
+                "{} vs {} > W/D/L {}/{}/{}",
+                participants[i].name, participants[j].name, result.wins, result.draws, result.losses
+            );
+
+            results[i][j] = result;
+            results[j][i] = MatchResult {
+                wins: result.losses,
+                draws: result.draws,
+                losses: result.wins,
+            };
+        }
+    }
+
+    return results;
+}
+
+// Maximum a posteriori Bradley-Terry ratings on the Elo scale, centred on 1500, with the standard error of each rating
+// from the diagonal of the Fisher information. Draws count as half a win for both sides.
+pub fn fit_ratings(results: &[Vec<MatchResult>], prior_draws: f64) -> Vec<(f64, f64)> {
+    let count = results.len();
+    let scale = 10f64.ln() / 400f64;
+
+    let mut ratings = vec![0f64; count];
+    let mut information = vec![0f64; count];
+
+    for _ in 0..1000 {
+        for i in 0..count {
+            let mut gradient = 0f64;
+
+            information[i] = 0f64;
+
+            for j in 0..count {
+                let games = results[i][j].games() as f64;
+
+                if i == j || games == 0f64 {
+                    continue;
+                }
+
+                let score = results[i][j].wins as f64 + 0.5 * results[i][j].draws as f64 + 0.5 * prior_draws;
+                let games = games + prior_draws;
+
+                let expected = 1f64 / (1f64 + 10f64.powf((ratings[j] - ratings[i]) / 400f64));
+
+                gradient += scale * (score - games * expected);
+                information[i] += scale * scale * games * expected * (1f64 - expected);
+            }
+
+            if information[i] > 0f64 {
+                ratings[i] += gradient / information[i];
+            }
+        }
+
+        let mean = ratings.iter().sum::<f64>() / count as f64;
+
+        for rating in ratings.iter_mut() {
+            *rating -= mean;
+        }
+    }
+
+    return ratings
+        .iter()
+        .zip(information.iter())
+        .map(|(rating, information)| {
+            (
+                1500f64 + rating,
+                if *information > 0f64 {
+                    1f64 / information.sqrt()
+                } else {
+                    f64::INFINITY
+                },
+            )
+        })
+        .collect();
+}
+
+// Plays the round robin, fits the ratings into the participants and returns the results.
+pub fn run_tournament(participants: &mut [Participant], config: &TournamentConfig) -> Vec<Vec<MatchResult>> {
+    let results = round_robin(participants, config);
+
+    for (participant, (elo, uncertainty)) in participants.iter_mut().zip(fit_ratings(&results, config.prior_draws)) {
+        participant.elo = elo;
+        participant.uncertainty = uncertainty;
+    }
+
+    return results;
+}
+
+// Participants sorted by rating, each with their total result against the field.
+fn ranking<'a>(participants: &'a [Participant], results: &[Vec<MatchResult>]) -> Vec<(&'a Participant, MatchResult)> {
+    let mut ranking: Vec<(&Participant, MatchResult)> = participants
+        .iter()
+        .zip(results.iter())
+        .map(|(participant, row)| {
+            let total = row.iter().fold(MatchResult::default(), |total, result| MatchResult {
+                wins: total.wins + result.wins,
+                draws: total.draws + result.draws,
+                losses: total.losses + result.losses,
+            });
+
+            (participant, total)
+        })
+        .collect();
+
+    ranking.sort_by(|a, b| b.0.elo.total_cmp(&a.0.elo));
+
+    return ranking;
+}
+
+pub fn print_ranking(participants: &[Participant], results: &[Vec<MatchResult>]) {
+    println!(
+        "{:>4} {:<20} {:>7} {:>6} {:>6} {:>6} {:>6}",
+        "Rank", "Name", "Elo", "+/-", "Games", "Score", "Cache"
+    );
+
+    for (rank, (participant, total)) in ranking(participants, results).into_iter().enumerate() {
+        println!(
+            "{:>4} {:<20} {:>7.0} {:>6.0} {:>6} {:>6.3} {:>6.2}",
+            rank + 1,
+            participant.name,
+            participant.elo,
+            participant.uncertainty,
+            total.games(),
+            total.score(),
+            participant.model.cache().hit_rate()
+        );
+    }
+}
+
+pub fn write_ranking_csv(path: &Path, participants: &[Participant], results: &[Vec<MatchResult>]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writeln!(writer, "rank,name,elo,uncertainty,games,wins,draws,losses")?;
+
+    for (rank, (participant, total)) in ranking(participants, results).into_iter().enumerate() {
+        writeln!(
+            writer,
+            "{},{},{:.1},{:.1},{},{},{},{}",
+            rank + 1,
+            participant.name,
+            participant.elo,
+            participant.uncertainty,
+            total.games(),
+            total.wins,
+            total.draws,
+            total.losses
+        )?;
+    }
+
+    return writer.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arena::MatchResult;
+    use crate::tournament::fit_ratings;
+
+    fn result(wins: usize, draws: usize, losses: usize) -> MatchResult {
+        MatchResult { wins, draws, losses }
+    }
+
+    #[test]
+    fn test_fit_ratings() {
+        let results = vec![
+            vec![result(0, 0, 0), result(15, 0, 5), result(20, 0, 0)],
+            vec![result(5, 0, 15), result(0, 0, 0), result(10, 0, 10)],
+            vec![result(0, 0, 20), result(10, 0, 10), result(0, 0, 0)],
+        ];
+
+        let ratings = fit_ratings(&results, 2f64);
+
+        assert!(ratings[0].0 > ratings[1].0);
+        assert!(ratings[1].0 > ratings[2].0);
+        assert!(ratings[0].1.is_finite() && ratings[0].1 > 0f64);
+
+        let mean = ratings.iter().map(|(elo, _)| elo).sum::<f64>() / 3f64;
+
+        assert!((mean - 1500f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_even_ratings() {
+        let results = vec![vec![result(0, 0, 0), result(5, 10, 5)], vec![result(5, 10, 5), result(0, 0, 0)]];
+
+        let ratings = fit_ratings(&results, 2f64);
+
+        assert!((ratings[0].0 - 1500f64).abs() < 1e-6);
+        assert!((ratings[1].0 - 1500f64).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,201 @@
+use rand::Rng;
+use tch::{Device, Kind, Tensor};
+
+use crate::connect_four::{ConnectFourGame, ConnectFourModel, LossWeights};
+use crate::game::Game;
+use crate::metrics::TrainingMetrics;
+use crate::optimizer::{Algorithm, Optimizer, Schedule};
+use crate::replay::{ReplayBuffer, Sampling};
+
+#[derive(Clone)]
+pub struct TrainingConfig {
+    pub batch_size: usize,
+    pub replay_capacity: usize,
+    pub sampling: Sampling,
+    // Optimizer steps taken after every self-play game.
+    pub steps_per_game: usize,
+    // Train every sample under a random symmetry of the board.
+    pub augment: bool,
+
+    pub algorithm: Algorithm,
+    pub learning_rate: f64,
+    pub schedule: Schedule,
+    // Steps over which the learning rate ramps up linearly from 0.
+    pub warmup_steps: usize,
+    pub weight_decay: f64,
+    // Maximum global gradient norm, None disables clipping.
+    pub gradient_clip: Option<f64>,
+    pub loss_weights: LossWeights,
+
+    // Weight of the auxiliary head losses, None trains a model without auxiliary heads.
+    pub auxiliary_weight: Option<f64>,
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        TrainingConfig {
+            batch_size: 256,
+            replay_capacity: 100_000,
+            sampling: Sampling::Uniform,
+            steps_per_game: 1,
+            augment: true,
+            algorithm: Algorithm::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                eps: 1e-8,
+            },
+            learning_rate: 1e-3,
+            schedule: Schedule::Constant,
+            warmup_steps: 0,
+            weight_decay: 0.01,
+            gradient_clip: None,
+            loss_weights: LossWeights::default(),
+            auxiliary_weight: None,
+        }
+    }
+}
+
+impl TrainingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        return self.schedule.validate();
+    }
+
+    // Recorded by name in the header of every checkpoint the pipeline saves.
+    pub fn hyperparameters(&self) -> Vec<(String, String)> {
+        let algorithm = match self.algorithm {
+            Algorithm::AdamW { beta1, beta2, eps } => format!("adamw({}, {}, {})", beta1, beta2, eps),
+            Algorithm::Sgd { momentum, nesterov } => format!("sgd({}, {})", momentum, nesterov),
+        };
+
+        let schedule = match self.schedule {
+            Schedule::Constant => "constant".to_string(),
+            Schedule::Step { interval, factor } => format!("step({}, {})", interval, factor),
+            Schedule::Cosine { total_steps, minimum } => format!("cosine({}, {})", total_steps, minimum),
+        };
+
+        let optional = |value: Option<f64>| value.map_or("none".to_string(), |value| value.to_string());
+
+        return [
+            ("batch_size", self.batch_size.to_string()),
+            ("replay_capacity", self.replay_capacity.to_string()),
+            ("algorithm", algorithm),
+            ("learning_rate", self.learning_rate.to_string()),
+            ("schedule", schedule),
+            ("warmup_steps", self.warmup_steps.to_string()),
+            ("weight_decay", self.weight_decay.to_string()),
+            ("gradient_clip", optional(self.gradient_clip)),
+            ("policy_weight", self.loss_weights.policy.to_string()),
+            ("value_weight", self.loss_weights.value.to_string()),
+            ("l2_weight", self.loss_weights.l2.to_string()),
+            ("auxiliary_weight", optional(self.auxiliary_weight)),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    }
+}
+
+// Normalised policy target, all zeros when there is none.
+fn policy_tensor(policy: &Option<[f32; 7]>) -> Tensor {
+    match policy {
+        Some(policy) => {
+            let policy = Tensor::from_slice(policy);
+
+            policy.divide(&policy.sum(Kind::Float))
+        }
+        None => Tensor::zeros([7], (Kind::Float, Device::Cpu)),
+    }
+}
+
+// Takes one optimizer step on a minibatch from the replay buffer and returns the metrics of the batch.
+pub fn train_step(
+    model: &ConnectFourModel,
+    optimizer: &mut Optimizer,
+    buffer: &mut ReplayBuffer,
+    config: &TrainingConfig,
+    rng: &mut impl Rng,
+) -> TrainingMetrics {
+    let device = model.device();
+
+    let (indices, weights) = buffer.sample(config.batch_size, rng);
+
+    let mut inputs = Vec::with_capacity(indices.len());
+    let mut legal = Vec::with_capacity(indices.len());
+    let mut target_policies = Vec::with_capacity(indices.len());
+    let mut target_scores = Vec::with_capacity(indices.len());
+    let mut target_moves_left = Vec::with_capacity(indices.len());
+    let mut target_opponent_policies = Vec::with_capacity(indices.len());
+
+    for &index in &indices {
+        let symmetry = if config.augment {
+            rng.random_range(0..ConnectFourGame::symmetry_count())
+        } else {
+            0
+        };
+
+        let sample = buffer.get(index).transformed::<ConnectFourGame>(symmetry);
+
+        let game = ConnectFourGame::from_moves(&sample.history);
+
+        inputs.push(model.encode(&game));
+        legal.push(model.legal_mask(&game));
+
+        // Fast search samples only train the value head, an all zero target makes their policy loss vanish.
+        target_policies.push(policy_tensor(&sample.policy_target));
+        target_scores.push(sample.value_target);
+
+        target_moves_left.push(sample.moves_left);
+        target_opponent_policies.push(policy_tensor(&sample.opponent_policy));
+    }
+
+    let inputs = Tensor::stack(&inputs, 0).to_device(device);
+    let legal = Tensor::stack(&legal, 0).to_device(device);
+    let target_policies = Tensor::stack(&target_policies, 0).to_device(device);
+    let target_scores = Tensor::from_slice(&target_scores).to_device(device);
+    let weights = Tensor::from_slice(&weights).to_kind(Kind::Float).to_device(device);
+
+    let (log_policy, score, auxiliary) = model.forward_training(&inputs, &legal, true);
+
+    let mut losses = model.batch_loss(&log_policy, &score, &target_policies, &target_scores, &config.loss_weights);
+
+    if let (Some(weight), Some((moves_left, opponent_log_policy))) = (config.auxiliary_weight, auxiliary) {
+        let target_moves_left = Tensor::from_slice(&target_moves_left).to_device(device);
+        let target_opponent_policies = Tensor::stack(&target_opponent_policies, 0).to_device(device);
+
+        losses = losses + model.auxiliary_loss(&moves_left, &opponent_log_policy, &target_moves_left, &target_opponent_policies) * weight;
+    }
+
+    let mut loss = (&losses * weights).mean(Kind::Float);
+
+    if config.loss_weights.l2 > 0f64 {
+        loss = loss + optimizer.squared_norm() * config.loss_weights.l2;
+    }
+
+    optimizer.learning_rate = config
+        .schedule
+        .learning_rate(config.learning_rate, config.warmup_steps, optimizer.steps as usize);
+
+    let mut metrics = TrainingMetrics {
+        learning_rate: optimizer.learning_rate,
+        ..Default::default()
+    };
+
+    metrics.measure(&log_policy, &score, &target_policies, &target_scores);
+
+    optimizer.zero_grad();
+    loss.backward();
+
+    metrics.gradient_norm = optimizer.clip_grad_norm(config.gradient_clip.unwrap_or(f64::INFINITY));
+
+    optimizer.step();
+
+    model.clear_cache();
+
+    let priorities = Vec::<f64>::try_from(losses.detach().to_kind(Kind::Double).to_device(Device::Cpu)).unwrap();
+
+    buffer.update_priorities(&indices, &priorities);
+
+    metrics.loss = loss.double_value(&[]);
+
+    return metrics;
+}
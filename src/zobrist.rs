@@ -0,0 +1,27 @@
+const fn splitmix64(state: u64) -> (u64, u64) {
+    let state = state.wrapping_add(0x9E3779B97F4A7C15);
+
+    let mut key = state;
+    key = (key ^ (key >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    key = (key ^ (key >> 27)).wrapping_mul(0x94D049BB133111EB);
+
+    (state, key ^ (key >> 31))
+}
+
+pub const fn zobrist_keys<const N: usize>(seed: u64) -> [u64; N] {
+    let mut keys = [0u64; N];
+    let mut state = seed;
+
+    let mut i = 0;
+
+    while i < N {
+        let (next_state, key) = splitmix64(state);
+
+        keys[i] = key;
+        state = next_state;
+
+        i += 1;
+    }
+
+    keys
+}